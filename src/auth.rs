@@ -0,0 +1,54 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// compares secrets without stopping at the first differing byte
+pub fn token_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// hex hmac-sha256 of msg keyed by key
+pub fn sign(key: &str, msg: &str) -> String {
+    // hmac takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).unwrap();
+    mac.input(msg.as_bytes());
+    mac.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// the same on primary and backup, so either can check reports for a game
+pub fn game_secret(server_key: &str, game: u32) -> String {
+    sign(server_key, &game.to_string())
+}
+
+// game reports and mqtt admin actions carry an hmac instead of the secret, an empty secret accepts nothing
+pub fn sig_ok(secret: &str, payload: &str, sig: &str) -> bool {
+    secret != "" && token_eq(&sign(secret, payload), sig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_eq() {
+        assert!(token_eq("abc", "abc"));
+        assert!(!token_eq("abc", "abd"));
+        assert!(!token_eq("abc", "abcd"));
+    }
+
+    #[test]
+    fn test_sign() {
+        // rfc 4231 test case 2
+        assert_eq!(sign("Jefe", "what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let secret = game_secret("key", 7);
+        assert_eq!(secret, game_secret("key", 7));
+        assert!(secret != game_secret("key", 8));
+        let payload = r#"{"game":7}"#;
+        assert!(sig_ok(&secret, payload, &sign(&secret, payload)));
+        assert!(!sig_ok(&secret, r#"{"game":8}"#, &sign(&secret, payload)));
+        assert!(!sig_ok("", payload, &sign("", payload)));
+    }
+}
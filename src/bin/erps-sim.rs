@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+use clap::{App, Arg};
+use failure::Error;
+
+use erps::config::{Config, DEFAULT_MODE};
+use erps::elo::*;
+use erps::matchmaker::*;
+use erps::rating::*;
+
+// xorshift64*, good enough for reproducible synthetic data
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed ^ 0x9E37_79B9_7F4A_7C15 | 1 }
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    // Box-Muller
+    fn normal(&mut self, mean: f64, sd: f64) -> f64 {
+        let u1 = self.next_f64().max(1e-12);
+        let u2 = self.next_f64();
+        mean + sd * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct SimPlayer {
+    skill: f64,
+    ng: i16,
+    rating: PlayerRating,
+    games: u32,
+    queued_at: Option<u64>,
+    busy_until: u64,
+}
+
+#[derive(Default)]
+struct SimStats {
+    queue_times: Vec<u64>,
+    rating_gaps: Vec<f64>,
+    skill_gaps: Vec<f64>,
+    upsets: u32,
+    games: u32,
+    convergence: Vec<(u64, f64)>,
+}
+
+fn percentile(v: &Vec<u64>, p: f64) -> u64 {
+    if v.len() == 0 {
+        return 0;
+    }
+    let i = ((v.len() - 1) as f64 * p).round() as usize;
+    v[i]
+}
+
+fn avg(v: &Vec<f64>) -> f64 {
+    if v.len() == 0 {
+        return 0.0;
+    }
+    v.iter().sum::<f64>() / v.len() as f64
+}
+
+// root mean square distance between rating and hidden skill
+fn rating_error(players: &Vec<SimPlayer>) -> f64 {
+    let sum: f64 = players.iter().map(|p| (p.ng as f64 - p.skill).powi(2)).sum();
+    (sum / players.len() as f64).sqrt()
+}
+
+fn team_skill(players: &Vec<SimPlayer>, team: &Vec<usize>) -> f64 {
+    team.iter().map(|i| players[*i].skill).sum::<f64>() / team.len() as f64
+}
+
+fn team_ng(players: &Vec<SimPlayer>, team: &Vec<usize>) -> f64 {
+    team.iter().map(|i| players[*i].ng as f64).sum::<f64>() / team.len() as f64
+}
+
+fn main() -> Result<(), Error> {
+    let matches = App::new("erps-sim")
+        .about("Deterministic matchmaking simulator")
+        .arg(Arg::with_name("PLAYERS").short("n").long("players").takes_value(true)
+            .help("number of synthetic players (1000)"))
+        .arg(Arg::with_name("TICKS").short("t").long("ticks").takes_value(true)
+            .help("simulated seconds to run (36000)"))
+        .arg(Arg::with_name("SEED").short("s").long("seed").takes_value(true)
+            .help("random seed (1)"))
+        .arg(Arg::with_name("CONFIG").short("c").long("config").takes_value(true)
+            .help("game mode config file (json)"))
+        .arg(Arg::with_name("MODE").short("m").long("mode").takes_value(true)
+            .help("game mode to simulate (ng)"))
+        .arg(Arg::with_name("GAME_LEN").long("game-len").takes_value(true)
+            .help("simulated game length in seconds (1800)"))
+        .arg(Arg::with_name("QUEUE_RATE").long("queue-rate").takes_value(true)
+            .help("chance per second an idle player queues (0.01)"))
+        .get_matches();
+
+    let n: usize = matches.value_of("PLAYERS").unwrap_or("1000").parse()?;
+    let ticks: u64 = matches.value_of("TICKS").unwrap_or("36000").parse()?;
+    let seed: u64 = matches.value_of("SEED").unwrap_or("1").parse()?;
+    let game_len: u64 = matches.value_of("GAME_LEN").unwrap_or("1800").parse()?;
+    let queue_rate: f64 = matches.value_of("QUEUE_RATE").unwrap_or("0.01").parse()?;
+    let mode_name = matches.value_of("MODE").unwrap_or(DEFAULT_MODE).to_owned();
+    let config = match matches.value_of("CONFIG") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mode = config.mode(&mode_name).clone();
+
+    let mut rng = Rng::new(seed);
+    let mut mm = create_matchmaker(&mode)?;
+    let rating = create_rating(&mode)?;
+    // hidden skill decides games with the plain elo curve whatever the backend
+    let elo = EloRank {k: 20.0};
+    let init = rating.initial();
+    let mut players: Vec<SimPlayer> = vec![];
+    for _ in 0..n {
+        players.push(SimPlayer { skill: rng.normal(1000.0, 200.0), ng: init.score.round() as i16, rating: init, ..Default::default() });
+    }
+
+    let mut rooms: BTreeMap<u32, Rc<RefCell<QueueRoomData>>> = BTreeMap::new();
+    let mut stats: SimStats = Default::default();
+
+    // the queue thread runs one matching pass per second, so one tick is one second
+    for now in 0..ticks {
+        for (i, p) in players.iter_mut().enumerate() {
+            if p.queued_at.is_none() && p.busy_until <= now && rng.next_f64() < queue_rate {
+                p.queued_at = Some(now);
+                let rid = i as u32 + 1;
+                rooms.insert(rid, Rc::new(RefCell::new(QueueRoomData {
+                    rid: rid,
+                    user_len: 1,
+                    avg_ng: p.ng,
+                    avg_rk: p.ng,
+                    avg_rd: p.rating.rd,
+                    queue_cnt: 1,
+                    mode: mode_name.clone(),
+                    ..Default::default()
+                })));
+            }
+        }
+
+        for game in mm.find_matches(&rooms) {
+            let mut teams: Vec<Vec<usize>> = vec![];
+            for g in &game.group {
+                let mut team = vec![];
+                for rid in g {
+                    rooms.remove(rid);
+                    let i = (*rid - 1) as usize;
+                    if let Some(t) = players[i].queued_at.take() {
+                        stats.queue_times.push(now - t);
+                    }
+                    players[i].busy_until = now + game_len;
+                    team.push(i);
+                }
+                teams.push(team);
+            }
+            if teams.len() < 2 {
+                continue;
+            }
+            if teams.len() > 2 {
+                // free for all, teams are placed by a noisy draw of their hidden skill
+                let mut order: Vec<(f64, Vec<usize>)> = teams.iter().map(|t| (rng.normal(team_skill(&players, t), 200.0), t.clone())).collect();
+                order.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+                stats.games += 1;
+                let placed: Vec<Vec<PlayerRating>> = order.iter().map(|(_, t)| t.iter().map(|i| players[*i].rating).collect()).collect();
+                let res = rating.update_placement(&placed);
+                for (k, (_, t)) in order.iter().enumerate() {
+                    let place = if k == 0 { 1 } else if k == order.len() - 1 { -1 } else { 0 };
+                    for (j, i) in t.iter().enumerate() {
+                        players[*i].rating = count_game(&res[k][j], place);
+                        players[*i].ng = res[k][j].score.round() as i16;
+                        players[*i].games += 1;
+                    }
+                }
+                continue;
+            }
+            // resolve the two teams by hidden skill
+            let (a, b) = (&teams[0], &teams[1]);
+            let pa = elo.get_expected(team_skill(&players, a) as f32, team_skill(&players, b) as f32) as f64;
+            let a_wins = rng.next_f64() < pa;
+            let (win, lose) = if a_wins { (a, b) } else { (b, a) };
+            if (a_wins && pa < 0.5) || (!a_wins && pa > 0.5) {
+                stats.upsets += 1;
+            }
+            stats.rating_gaps.push((team_ng(&players, a) - team_ng(&players, b)).abs());
+            stats.skill_gaps.push((team_skill(&players, a) - team_skill(&players, b)).abs());
+            stats.games += 1;
+
+            let win_r: Vec<PlayerRating> = win.iter().map(|i| players[*i].rating).collect();
+            let lose_r: Vec<PlayerRating> = lose.iter().map(|i| players[*i].rating).collect();
+            let (rw, rl) = rating.update_teams(&win_r, &lose_r, false);
+            for (j, i) in win.iter().enumerate() {
+                players[*i].rating = count_game(&rw[j], 1);
+                players[*i].ng = rw[j].score.round() as i16;
+                players[*i].games += 1;
+            }
+            for (j, i) in lose.iter().enumerate() {
+                players[*i].rating = count_game(&rl[j], -1);
+                players[*i].ng = rl[j].score.round() as i16;
+                players[*i].games += 1;
+            }
+        }
+
+        if now % 3600 == 0 {
+            stats.convergence.push((now, rating_error(&players)));
+        }
+    }
+
+    stats.queue_times.sort();
+    println!("mode: {}, matchmaker: {}, rating: {}, seed: {}", mode_name, mode.matchmaker, mode.rating, seed);
+    println!("players: {}, simulated: {}s, games: {}", n, ticks, stats.games);
+    println!("queue time (s): p50 {}, p90 {}, p99 {}, max {}",
+        percentile(&stats.queue_times, 0.5),
+        percentile(&stats.queue_times, 0.9),
+        percentile(&stats.queue_times, 0.99),
+        percentile(&stats.queue_times, 1.0));
+    println!("still queued: {}", rooms.len());
+    println!("match quality: avg rating gap {:.1}, avg skill gap {:.1}, upsets {:.1}%",
+        avg(&stats.rating_gaps),
+        avg(&stats.skill_gaps),
+        if stats.games > 0 { stats.upsets as f64 * 100.0 / stats.games as f64 } else { 0.0 });
+    println!("rating convergence (rms rating - skill):");
+    for (t, e) in &stats.convergence {
+        println!("  {:>6}s {:.1}", t, e);
+    }
+    println!("  {:>6}s {:.1}", ticks, rating_error(&players));
+    Ok(())
+}
@@ -0,0 +1,314 @@
+use serde_derive::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use failure::Error;
+
+use crate::trueskill::TrueSkill;
+use crate::tier::TierConfig;
+use crate::matchmaker::create_matchmaker;
+use crate::rating::create_rating;
+
+pub const DEFAULT_MODE: &str = "ng";
+pub const TEAM_SIZE: i16 = 1;
+pub const MATCH_SIZE: usize = 2;
+pub const SCORE_INTERVAL: i16 = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KBracket {
+    pub min_score: f32,
+    pub k: f32,
+}
+
+// elo k per player: high while provisional, by rating bracket after that,
+// scaled up on long win or loss streaks
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct KSchedule {
+    pub provisional_games: u32,
+    pub provisional_k: f32,
+    // sorted by min_score, the last bracket at or below the score applies
+    pub brackets: Vec<KBracket>,
+    pub streak_len: i32,
+    pub streak_scale: f32,
+}
+
+impl Default for KSchedule {
+    fn default() -> KSchedule {
+        KSchedule {
+            provisional_games: 0,
+            provisional_k: 0.0,
+            brackets: vec![],
+            streak_len: 0,
+            // a streak_len set without a scale must not zero k
+            streak_scale: 1.0,
+        }
+    }
+}
+
+impl KSchedule {
+    pub fn k(&self, base: f32, games: u32, score: f32, streak: i32) -> f32 {
+        if games < self.provisional_games {
+            return self.provisional_k;
+        }
+        let mut k = base;
+        for b in &self.brackets {
+            if score >= b.min_score {
+                k = b.k;
+            }
+        }
+        if self.streak_len > 0 && streak.abs() >= self.streak_len {
+            k *= self.streak_scale;
+        }
+        k
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ModeConfig {
+    pub matchmaker: String,
+    pub team_size: i16,
+    pub match_size: usize,
+    pub score_interval: i16,
+    // extra score per additional party member, premades play above their average
+    pub premade_bonus: i16,
+    // score penalty per player of difference between the largest parties of two teams
+    pub party_size_penalty: i16,
+    // parties of at least this size are never matched with or against solo players, 0 disables
+    pub solo_party_limit: i16,
+    // max ng difference between the best and worst member of a room, 0 disables
+    pub max_party_spread: i16,
+    // 0.0 queues a room at its mean ng, 1.0 at its best member's ng
+    pub party_top_weight: f32,
+    // one role per team slot, e.g. ["top", "jungle", "mid", "carry", "support"], empty disables roles
+    pub role_template: Vec<String>,
+    // rating backend used to settle games, "elo", "glicko2" or "trueskill"
+    pub rating: String,
+    pub elo_k: f32,
+    pub k_schedule: KSchedule,
+    pub glicko_tau: f32,
+    // trueskill sigma is stored apart from the glicko2 rd, see rating::backend_name
+    pub trueskill_beta: f32,
+    pub trueskill_tau: f32,
+    pub draw_probability: f32,
+    // extra search range per point of rating deviation, lets uncertain players match wider
+    pub rd_search_scale: f32,
+    // games played before a visible rank (rk) is shown, ng stays the hidden mmr
+    pub placement_games: u32,
+    // rk starts this far below ng when placement ends
+    pub placement_rank_offset: i16,
+    // share of the remaining ng - rk gap closed every game after placement
+    pub rank_pull: f32,
+}
+
+impl Default for ModeConfig {
+    fn default() -> ModeConfig {
+        ModeConfig {
+            matchmaker: "greedy".to_string(),
+            team_size: TEAM_SIZE,
+            match_size: MATCH_SIZE,
+            score_interval: SCORE_INTERVAL,
+            premade_bonus: 0,
+            party_size_penalty: 0,
+            solo_party_limit: 0,
+            max_party_spread: 0,
+            party_top_weight: 0.0,
+            role_template: vec![],
+            rating: "elo".to_string(),
+            elo_k: 20.0,
+            k_schedule: Default::default(),
+            glicko_tau: 0.5,
+            trueskill_beta: TrueSkill::default().beta,
+            trueskill_tau: TrueSkill::default().tau,
+            draw_probability: TrueSkill::default().draw_probability,
+            rd_search_scale: 0.0,
+            placement_games: 10,
+            placement_rank_offset: 0,
+            rank_pull: 0.2,
+        }
+    }
+}
+
+// soft reset applied to ng and rk when a new season starts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SeasonConfig {
+    pub reset_mean: i16,
+    // share of the distance to the mean that is kept, 1.0 keeps ratings as they are
+    pub reset_keep: f32,
+}
+
+impl Default for SeasonConfig {
+    fn default() -> SeasonConfig {
+        SeasonConfig {
+            reset_mean: 1000,
+            reset_keep: 0.5,
+        }
+    }
+}
+
+impl SeasonConfig {
+    pub fn soft_reset(&self, score: i16) -> i16 {
+        (self.reset_mean as f64 + (score - self.reset_mean) as f64 * self.reset_keep as f64).round() as i16
+    }
+}
+
+// inactive players above the threshold lose rk and gain rating deviation once a day
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DecayConfig {
+    // rk above which decay applies, rk never decays below it, 0 disables decay
+    pub threshold: i16,
+    pub inactive_days: u64,
+    // rk lost per day of inactivity
+    pub rk_amount: i16,
+    // rating deviation added per day of inactivity, capped at the initial deviation
+    pub rd_amount: f32,
+}
+
+impl Default for DecayConfig {
+    fn default() -> DecayConfig {
+        DecayConfig {
+            threshold: 1200,
+            inactive_days: 14,
+            rk_amount: 10,
+            rd_amount: 5.0,
+        }
+    }
+}
+
+impl DecayConfig {
+    pub fn due(&self, rk: i16, last_game: u64, last_decay: u64, now: u64) -> bool {
+        self.threshold > 0 && rk > self.threshold
+            && now >= last_game + self.inactive_days * 86400
+            && now >= last_decay + 86400
+    }
+}
+
+// hero statistics are rebuilt from game_info and the rating ledger on this interval
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HeroStatsConfig {
+    // 0 disables the job
+    pub interval_secs: u64,
+}
+
+impl Default for HeroStatsConfig {
+    fn default() -> HeroStatsConfig {
+        HeroStatsConfig {
+            interval_secs: 3600,
+        }
+    }
+}
+
+// read-only state endpoints and token protected admin actions
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    // the admin endpoints are only guarded by the token, keep them off public interfaces
+    pub bind: String,
+    // 0 disables the server
+    pub port: u16,
+    // sent as "Authorization: Bearer <token>", empty disables the admin endpoints
+    pub admin_token: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            admin_token: "".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub default: ModeConfig,
+    pub modes: BTreeMap<String, ModeConfig>,
+    // rk is shared by all modes, so are the tiers on top of it
+    pub tiers: TierConfig,
+    pub season: SeasonConfig,
+    pub decay: DecayConfig,
+    pub hero_stats: HeroStatsConfig,
+    pub http: HttpConfig,
+    // shared by primary and backup, game secrets are derived from it
+    pub server_key: String,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let s = fs::read_to_string(path)?;
+        let config: Config = serde_json::from_str(&s)?;
+        config.check()?;
+        Ok(config)
+    }
+
+    // catch typos at startup instead of when the first room queues
+    pub fn check(&self) -> Result<(), Error> {
+        if self.tiers.tiers.is_empty() {
+            return Err(failure::err_msg("tiers must not be empty"));
+        }
+        create_matchmaker(&self.default)?;
+        create_rating(&self.default)?;
+        for m in self.modes.values() {
+            create_matchmaker(m)?;
+            create_rating(m)?;
+        }
+        Ok(())
+    }
+
+    // queues only accept the default mode and the ones listed in the config file
+    pub fn has_mode(&self, name: &str) -> bool {
+        name == DEFAULT_MODE || self.modes.contains_key(name)
+    }
+
+    // modes not listed in the config file use the default settings
+    pub fn mode(&self, name: &str) -> &ModeConfig {
+        match self.modes.get(name) {
+            Some(m) => m,
+            None => &self.default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_k_schedule() {
+        let s: KSchedule = serde_json::from_str(r#"{"streak_len": 3}"#).unwrap();
+        assert_eq!(s.k(20.0, 50, 1000.0, 5), 20.0);
+
+        let s = KSchedule {
+            provisional_games: 10,
+            provisional_k: 40.0,
+            brackets: vec![KBracket {min_score: 1200.0, k: 16.0}, KBracket {min_score: 1500.0, k: 10.0}],
+            streak_len: 3,
+            streak_scale: 1.5,
+        };
+        assert_eq!(s.k(20.0, 5, 1600.0, 0), 40.0);
+        assert_eq!(s.k(20.0, 10, 1000.0, 0), 20.0);
+        assert_eq!(s.k(20.0, 10, 1300.0, 0), 16.0);
+        assert_eq!(s.k(20.0, 10, 1600.0, 0), 10.0);
+        assert_eq!(s.k(20.0, 10, 1000.0, 2), 20.0);
+        assert_eq!(s.k(20.0, 10, 1000.0, -3), 30.0);
+        assert_eq!(s.k(20.0, 10, 1300.0, 4), 24.0);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut config = Config::default();
+        config.tiers.tiers.push(crate::tier::Tier {name: "Bronze".to_string(), min_rk: 0, divisions: 1});
+        assert!(config.check().is_ok());
+        let mut mode = ModeConfig::default();
+        mode.rating = "glicko".to_string();
+        config.modes.insert("ranked".to_string(), mode);
+        let err = config.check().unwrap_err();
+        println!("{}", err);
+        assert_eq!(err.to_string(), "unknown rating glicko");
+    }
+}
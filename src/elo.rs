@@ -57,7 +57,7 @@ impl EloRank {
         let m = mean(team);
         let mut rest = vec![];
         let mut a = win_mount as f32 *scale + 0.25;
-        for (i, score) in team.iter().enumerate() {
+        for score in team.iter() {
             let ewin = self.get_expected(*score as f32, m as f32);
             let rwin = self.rating(ewin as f32, a, *score as f32);
             a -= scale;
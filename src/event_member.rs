@@ -16,6 +16,13 @@ use crate::room::User;
 #[derive(Serialize, Deserialize)]
 struct LoginData {
     id: String,
+    #[serde(default)]
+    region: String,
+}
+
+// regions end up in mqtt topics and leaderboard keys
+fn region_ok(region: &str) -> bool {
+    region.len() <= 16 && region.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,7 +35,10 @@ pub fn login(id: String, v: Value, pool: mysql::Pool, sender: Sender<RoomEventDa
  -> std::result::Result<(), Error>
 {
     let data: LoginData = serde_json::from_value(v)?;
-    sender.send(RoomEventData::Login(UserLoginData {u: User { id: id.clone(), hero: "default name".to_string(), online: true, ng: 1000, rk: 1000, ..Default::default()}, dataid: data.id}));
+    if !region_ok(&data.region) {
+        return Err(failure::err_msg(format!("login {}: bad region {:?}", id, data.region)));
+    }
+    sender.send(RoomEventData::Login(UserLoginData {u: User { id: id.clone(), hero: "default name".to_string(), online: true, ng: 1000, rk: 1000, region: data.region, ..Default::default()}, dataid: data.id}));
     /*
     let mut conn = pool.get_conn()?;
     let sql = format!(r#"select a.score as ng, b.score as rk, name from user as c 
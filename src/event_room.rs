@@ -7,13 +7,13 @@ use std::io::{ErrorKind};
 use log::{info, warn, error, trace};
 use std::thread;
 use std::panic;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use ::futures::Future;
 use mysql;
 use std::sync::{Arc, Mutex, Condvar, RwLock};
 use crossbeam_channel::{bounded, tick, Sender, Receiver, select};
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, BTreeMap, BTreeSet};
 use std::cell::RefCell;
 use std::rc::Rc;
 use failure::Error;
@@ -23,11 +23,18 @@ use rayon::slice::*;
 use crate::room::*;
 use crate::msg::*;
 use crate::elo::*;
+use crate::config::*;
+use crate::matchmaker::*;
+use crate::glicko2::{DEFAULT_RD, DEFAULT_VOL};
+use crate::rating::*;
+use crate::tier::*;
+use crate::leaderboard::*;
+use crate::herostats::*;
+use crate::ledger::{LedgerRow, VoidGames};
+use crate::metrics;
+use crate::auth::{game_secret, sig_ok, sign};
 use std::process::Command;
-
-const TEAM_SIZE: i16 = 1;
-const MATCH_SIZE: usize = 2;
-const SCORE_INTERVAL: i16 = 100;
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CreateRoomData {
@@ -64,6 +71,12 @@ pub struct UserNGHeroData {
     pub hero: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserRoleData {
+    pub id: String,
+    pub roles: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserLogoutData {
     pub id: String,
@@ -73,6 +86,8 @@ pub struct UserLogoutData {
 pub struct StartQueueData {
     pub id: String,
     pub action: String,
+    #[serde(default)]
+    pub mode: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -84,6 +99,8 @@ pub struct CancelQueueData {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PreGameData {
     pub rid: Vec<Vec<u32>>,
+    pub mode: String,
+    pub roles: BTreeMap<String, String>,
 }
 
 
@@ -110,6 +127,12 @@ pub struct LeaveData {
 pub struct StartGameData {
     pub game: u32,
     pub action: String,
+    // hex hmac-sha256 of the payload keyed by the game's secret
+    #[serde(default)]
+    pub sig: String,
+    // what sig covers, filled in from the received json
+    #[serde(skip)]
+    pub payload: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -124,6 +147,7 @@ pub struct HeroCell {
     pub team: u16,
     pub name: String,
     pub hero: String,
+    pub role: String,
     pub buff: BTreeMap<String, f32>,
     pub tags: Vec<String>,
 }
@@ -131,13 +155,36 @@ pub struct HeroCell {
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct GameOverData {
     pub game: u32,  
+    #[serde(default)]
     pub win: Vec<String>,
+    #[serde(default)]
     pub lose: Vec<String>,
+    // win and lose are the two teams of a drawn game
+    #[serde(default)]
+    pub draw: bool,
+    // teams from first to last place, used instead of win/lose when set
+    #[serde(default)]
+    pub placements: Vec<Vec<String>>,
+    // hex hmac-sha256 of the payload keyed by the game's secret
+    #[serde(default)]
+    pub sig: String,
+    // what sig covers, filled in from the received json
+    #[serde(skip)]
+    pub payload: String,
+    // topic id of the publisher, filled in by game_over
+    #[serde(skip)]
+    pub reporter: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct GameCloseData {
     pub game: u32,
+    // hex hmac-sha256 of the payload keyed by the game's secret
+    #[serde(default)]
+    pub sig: String,
+    // what sig covers, filled in from the received json
+    #[serde(skip)]
+    pub payload: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -150,6 +197,161 @@ pub struct ReconnectData {
     pub id: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SeasonData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+    // hex hmac-sha256 of the payload keyed by the admin token, the token itself never goes over mqtt
+    #[serde(default)]
+    pub sig: String,
+    // what sig covers, filled in from the received json
+    #[serde(skip)]
+    pub payload: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SeasonHistoryData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct LeaderboardQueryData {
+    // requester, filled in from the topic when empty
+    pub id: String,
+    // "rk", "ng" is only served on /admin/leaderboard
+    pub mode: String,
+    // "" for the global board
+    pub region: String,
+    // 0 for the current season
+    pub season: u32,
+    // "top" or "around"
+    pub kind: String,
+    pub offset: usize,
+    pub count: usize,
+}
+
+// a request from the http server, answered with a status code and a json body
+#[derive(Clone, Debug)]
+pub struct HttpRequestData {
+    pub path: String,
+    pub params: BTreeMap<String, String>,
+    pub reply: Sender<(u16, String)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RollbackData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+    pub game: u32,
+    // hex hmac-sha256 of the payload keyed by the admin token
+    #[serde(default)]
+    pub sig: String,
+    // what sig covers, filled in from the received json
+    #[serde(skip)]
+    pub payload: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProfileData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MatchHistoryData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+    // 0 based
+    #[serde(default)]
+    pub page: u32,
+    #[serde(default)]
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HeroStatsQueryData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+    // empty for every hero
+    #[serde(default)]
+    pub hero: String,
+    // tier name or "all", empty for every bracket
+    #[serde(default)]
+    pub bracket: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SeasonCell {
+    pub season: u32,
+    pub ng: i16,
+    pub rk: i16,
+    pub tier: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LedgerCell {
+    pub game: u32,
+    pub mode: String,
+    pub time: u64,
+    pub ng_before: i16,
+    pub ng_after: i16,
+    pub delta: i16,
+    pub rk_before: i16,
+    pub rk_after: i16,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HeroCount {
+    pub hero: String,
+    pub games: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ItemCount {
+    pub item: String,
+    pub games: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MatchCell {
+    pub game: u32,
+    pub hero: String,
+    pub level: u16,
+    pub damage: u32,
+    pub take_damage: u32,
+    pub heal: u32,
+    pub kill: u32,
+    pub death: u32,
+    pub assist: u32,
+    // win, loss, draw or place n, empty when the game was not rated
+    pub result: String,
+    pub rk_delta: i16,
+    pub void: bool,
+    pub equ: Vec<String>,
+    pub gift: BTreeMap<String, u16>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RatingHistoryData {
+    // filled in from the topic
+    #[serde(default)]
+    pub id: String,
+    // only rows of this mode when set
+    #[serde(default)]
+    pub mode: String,
+    #[serde(default)]
+    pub limit: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct DeadData {
     pub ServerDead: String,
@@ -159,6 +361,22 @@ pub struct DeadData {
 pub struct GameInfoData {
     pub game: u32,
     pub users: Vec<UserInfoData>,
+    // hex hmac-sha256 of the payload keyed by the game's secret
+    #[serde(default)]
+    pub sig: String,
+    // what sig covers, filled in from the received json
+    #[serde(skip)]
+    pub payload: String,
+    // topic id of the publisher, filled in by game_info
+    #[serde(skip)]
+    pub reporter: String,
+}
+
+// a game server report that could not be parsed
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BadReportData {
+    pub topic: String,
+    pub reason: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -167,22 +385,15 @@ pub struct UserInfoData {
     pub hero: String,
     pub level: u16,
     pub equ: Vec<String>,
-    pub damage: u16,
-    pub take_damage: u16, 
-    pub heal: u16,
-    pub kill: u16,
-    pub death: u16,
-    pub assist: u16,
-    pub gift: UserGift,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
-pub struct UserGift {
-    pub a: u16,
-    pub b: u16,
-    pub c: u16,
-    pub d: u16,
-    pub e: u16,
+    pub damage: u32,
+    pub take_damage: u32, 
+    pub heal: u32,
+    pub kill: u32,
+    pub death: u32,
+    pub assist: u32,
+    // gift name to points, the old a-e object parses as a map too
+    #[serde(default)]
+    pub gift: BTreeMap<String, u16>,
 }
 
 #[derive(Debug)]
@@ -193,6 +404,7 @@ pub enum RoomEventData {
     Create(CreateRoomData),
     Close(CloseRoomData),
     ChooseNGHero(UserNGHeroData),
+    ChooseRole(UserRoleData),
     Invite(InviteRoomData),
     Join(JoinRoomData),
     StartQueue(StartQueueData),
@@ -204,22 +416,84 @@ pub enum RoomEventData {
     StartGame(StartGameData),
     GameOver(GameOverData),
     GameInfo(GameInfoData),
+    BadReport(BadReportData),
     GameClose(GameCloseData),
     Status(StatusData),
     Reconnect(ReconnectData),
     MainServerDead(DeadData),
+    SeasonStart(SeasonData),
+    SeasonEnd(SeasonData),
+    SeasonHistory(SeasonHistoryData),
+    RatingHistory(RatingHistoryData),
+    Rollback(RollbackData),
+    LeaderboardQuery(LeaderboardQueryData),
+    Profile(ProfileData),
+    MatchHistory(MatchHistoryData),
+    HeroStatsQuery(HeroStatsQueryData),
+    Http(HttpRequestData),
 }
 
 #[derive(Clone, Debug)]
 pub struct SqlLoginData {
     pub id: String,
     pub name: String,
+    pub rating: PlayerRating,
+    pub region: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SqlRegionData {
+    pub id: String,
+    pub region: String,
 }
 
 #[derive(Clone, Debug)]
 pub struct SqlScoreData {
     pub id: String,
     pub score: i16,
+    pub rk: i16,
+    pub backend: String,
+    pub rd: f32,
+    pub vol: f32,
+    pub games: u32,
+    pub streak: i32,
+    pub last_game: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SqlLedgerData {
+    pub id: String,
+    pub game: u32,
+    pub mode: String,
+    pub time: u64,
+    pub ng_before: i16,
+    pub ng_after: i16,
+    pub rk_before: i16,
+    pub rk_after: i16,
+    pub rd_before: f32,
+    pub rd_after: f32,
+    pub vol_before: f32,
+    pub tier_before: u16,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SqlVoidData {
+    pub game: u32,
+    pub time: u64,
+    pub admin: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SqlDecayData {
+    pub id: String,
+    pub time: u64,
+    pub rk_before: i16,
+    pub rk_after: i16,
+    pub rd_before: f32,
+    pub rd_after: f32,
+    pub pending: i16,
+    pub deviations: BTreeMap<String, Deviation>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -228,50 +502,60 @@ pub struct SqlGameInfoData {
     pub id: String,
     pub hero: String,
     pub level: u16,
-    pub equ: String,
-    pub damage: u16, 
-    pub take_damage: u16,
-    pub heal: u16,
-    pub kill: u16,
-    pub death: u16,
-    pub assist: u16,
-    pub gift: UserGift,
+    // in slot order
+    pub equ: Vec<String>,
+    pub damage: u32, 
+    pub take_damage: u32,
+    pub heal: u32,
+    pub kill: u32,
+    pub death: u32,
+    pub assist: u32,
+    pub gift: BTreeMap<String, u16>,
 }
 
 
-pub enum SqlData {
-    Login(SqlLoginData),
-    UpdateScore(SqlScoreData),
-    UpdateGameInfo(SqlGameInfoData)
+#[derive(Clone, Debug)]
+pub struct SqlTierData {
+    pub id: String,
+    pub season: u32,
+    pub tier: TierState,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
-pub struct QueueRoomData {
-    pub rid: u32,
-    pub gid: u32,
-    pub user_len: i16,
-    pub avg_ng: i16,
-    pub avg_rk: i16,
-    pub ready: i8,
-    pub queue_cnt: i16,
+#[derive(Clone, Debug)]
+pub struct SqlSeasonUserData {
+    pub id: String,
+    pub ng: i16,
+    pub rk: i16,
+    pub level: u16,
+    pub tier: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
-pub struct ReadyGroupData {
-    pub gid: u32,
-    pub rid: Vec<u32>,
-    pub user_len: i16,
-    pub avg_ng: i16,
-    pub avg_rk: i16,
-    pub game_status: u16,
-    pub queue_cnt: i16,
+#[derive(Clone, Debug)]
+pub struct SqlSeasonEndData {
+    pub season: u32,
+    pub users: Vec<SqlSeasonUserData>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
-pub struct ReadyGameData {
-    pub gid: Vec<u32>,
-    pub group: Vec<Vec<u32>>,
-    pub team_len: usize,
+#[derive(Clone, Debug)]
+pub struct SqlSeasonStartData {
+    pub season: u32,
+    pub reset: SeasonConfig,
+    // users already placed get their new season tier right away
+    pub tiers: Vec<(String, TierState)>,
+}
+
+pub enum SqlData {
+    Login(SqlLoginData),
+    UpdateScore(SqlScoreData),
+    UpdateTier(SqlTierData),
+    SeasonEnd(SqlSeasonEndData),
+    SeasonStart(SqlSeasonStartData),
+    Decay(SqlDecayData),
+    Ledger(SqlLedgerData),
+    VoidGame(SqlVoidData),
+    UpdateRegion(SqlRegionData),
+    DecayNotified(String),
+    UpdateGameInfo(SqlGameInfoData)
 }
 
 pub struct RemoveRoomData {
@@ -281,6 +565,11 @@ pub struct RemoveRoomData {
 pub enum QueueData {
     UpdateRoom(QueueRoomData),
     RemoveRoom(RemoveRoomData),
+    Snapshot(Sender<(u16, String)>),
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 // Prints the elapsed time.
@@ -298,8 +587,8 @@ fn SendGameList(game: &Rc<RefCell<FightGame>>, msgtx: &Sender<MqttMsg>, conn: &m
     res.game = game.borrow().game_id;
     for (i, t) in game.borrow().teams.iter().enumerate() {
         let ids = t.borrow().get_users_id_hero();
-        for (id, name, hero) in &ids {
-            let h: HeroCell = HeroCell {id:id.clone(), team: (i+1) as u16, name:name.clone(), hero:hero.clone(), ..Default::default() };
+        for (id, name, hero, role) in &ids {
+            let h: HeroCell = HeroCell {id:id.clone(), team: (i+1) as u16, name:name.clone(), hero:hero.clone(), role:role.clone(), ..Default::default() };
             res.member.push(h);
         }
     }
@@ -333,6 +622,22 @@ fn get_game_id_by_id(id: &String, users: &BTreeMap<String, Rc<RefCell<User>>>) -
     return 0;
 }
 
+fn get_queue_data(r: &Rc<RefCell<RoomData>>, mode: &ModeConfig) -> QueueRoomData {
+    QueueRoomData {
+        rid: r.borrow().rid.clone(),
+        gid: 0,
+        user_len: r.borrow().users.len().clone() as i16,
+        avg_ng: r.borrow().party_ng(mode.party_top_weight),
+        avg_rk: r.borrow().avg_rk.clone(),
+        ready: 0,
+        queue_cnt: 1,
+        mode: r.borrow().mode.clone(),
+        users: r.borrow().users.iter().map(|u| u.borrow().id.clone()).collect(),
+        roles: r.borrow().users.iter().map(|u| u.borrow().roles.clone()).collect(),
+        avg_rd: r.borrow().avg_rd(backend_name(mode)),
+    }
+}
+
 fn get_user(id: &String, users: &BTreeMap<String, Rc<RefCell<User>>>) -> Option<Rc<RefCell<User>>> {
     let u = users.get(id);
     if let Some(u) = u {
@@ -341,6 +646,93 @@ fn get_user(id: &String, users: &BTreeMap<String, Rc<RefCell<User>>>) -> Option<
     None
 }
 
+fn check_sig(game: Option<&Rc<RefCell<FightGame>>>, payload: &str, sig: &str) -> bool {
+    match game {
+        Some(g) => sig_ok(&g.borrow().secret, payload, sig),
+        None => false,
+    }
+}
+
+// game reports and admin actions are signed over their json without sig, keys sorted and no whitespace
+fn signed_payload(v: &Value) -> String {
+    let mut v = v.clone();
+    if let Value::Object(m) = &mut v {
+        m.remove("sig");
+    }
+    v.to_string()
+}
+
+// settled games are remembered this long to turn away repeated reports and take late game_info
+const SETTLED_KEEP: Duration = Duration::from_secs(600);
+
+// what game_info still needs once game_over has taken the game out of GameingGroups
+struct SettledGame {
+    time: Instant,
+    // only game_id, user_names, secret and info_ids are kept
+    game: Rc<RefCell<FightGame>>,
+}
+
+// a result is only accepted once, signed with the game's secret and for exactly its roster
+fn check_game_over(x: &GameOverData, game: Option<&Rc<RefCell<FightGame>>>, settled: &BTreeMap<u32, SettledGame>) -> Option<String> {
+    if settled.contains_key(&x.game) {
+        return Some(format!("game {} already settled", x.game));
+    }
+    let game = match game {
+        Some(g) => g,
+        None => return Some(format!("game {} not found", x.game)),
+    };
+    if !check_sig(Some(game), &x.payload, &x.sig) {
+        return Some(format!("game {} bad signature", x.game));
+    }
+    if x.placements.len() > 0 && (x.win.len() > 0 || x.lose.len() > 0) {
+        return Some(format!("game {} reports both placements and win/lose", x.game));
+    }
+    let mut reported: Vec<String> = vec![];
+    if x.placements.len() > 0 {
+        for t in &x.placements {
+            reported.extend(t.iter().cloned());
+        }
+    } else {
+        reported.extend(x.win.iter().cloned());
+        reported.extend(x.lose.iter().cloned());
+    }
+    let mut roster = game.borrow().user_names.clone();
+    reported.sort();
+    roster.sort();
+    if reported != roster {
+        return Some(format!("players do not match game {}", x.game));
+    }
+    None
+}
+
+// stats are only taken signed with the running game's secret, once per rostered player
+fn check_game_info(x: &GameInfoData, game: Option<&Rc<RefCell<FightGame>>>) -> Option<String> {
+    let game = match game {
+        Some(g) => g,
+        None => return Some(format!("game {} not found", x.game)),
+    };
+    if !check_sig(Some(game), &x.payload, &x.sig) {
+        return Some(format!("game {} bad signature", x.game));
+    }
+    if x.users.len() == 0 {
+        return Some(format!("no players in game {}", x.game));
+    }
+    let g = game.borrow();
+    let mut seen: BTreeSet<&String> = BTreeSet::new();
+    for u in &x.users {
+        if !g.user_names.contains(&u.id) {
+            return Some(format!("player {} is not in game {}", u.id, x.game));
+        }
+        if !seen.insert(&u.id) || g.info_ids.contains(&u.id) {
+            return Some(format!("player {} already reported in game {}", u.id, x.game));
+        }
+        if u.hero == "" {
+            return Some(format!("player {} has no hero", u.id));
+        }
+    }
+    None
+}
+
 fn get_users(ids: &Vec<String>, users: &BTreeMap<String, Rc<RefCell<User>>>) -> Result<Vec<Rc<RefCell<User>>>, Error> {
     let mut res: Vec<Rc<RefCell<User>>> = vec![];
     for id in ids {
@@ -357,12 +749,33 @@ fn get_users(ids: &Vec<String>, users: &BTreeMap<String, Rc<RefCell<User>>>) ->
     }
 }
 
-fn user_score(u: &Rc<RefCell<User>>, value: i16, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>, conn: &mut mysql::PooledConn) -> Result<(), Error> {
+// ng is the hidden mmr, clients should show placement progress and then rk,
+// ng and rk stay in the message for older clients
+// players in placement only see their progress, the rating fields appear once it is done
+fn login_msg(u: &User, mode: &ModeConfig) -> String {
+    if u.games < mode.placement_games {
+        format!(r#"{{"msg":"ok", "placement":true, "played":{}, "total":{} }}"#, u.games, mode.placement_games)
+    } else {
+        format!(r#"{{"msg":"ok", "ng":{}, "rk":{}, "placement":false }}"#, u.ng, u.rk)
+    }
+}
+
+// the backup keeps its users in step but leaves publishing and sql to the live instance
+fn user_score(u: &Rc<RefCell<User>>, value: i16, mode: &ModeConfig, live: bool, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>, conn: &mut mysql::PooledConn) -> Result<(), Error> {
+    let old_ng = u.borrow().ng;
     u.borrow_mut().ng += value;
+    let rk = next_rank(mode, u.borrow().games, old_ng, u.borrow().ng, u.borrow().rk);
+    u.borrow_mut().rk = rk;
+    if !live {
+        return Ok(());
+    }
     msgtx.try_send(MqttMsg{topic:format!("member/{}/res/login", u.borrow().id), 
-        msg: format!(r#"{{"msg":"ok", "ng":{}, "rk":{} }}"#, u.borrow().ng, u.borrow().rk)})?;
+        msg: login_msg(&u.borrow(), mode)})?;
     //println!("Update!");
-    sender.send(SqlData::UpdateScore(SqlScoreData {id: u.borrow().id.clone(), score: u.borrow().ng.clone()}));
+    let backend = backend_name(mode);
+    let dev = u.borrow().deviation(backend);
+    sender.send(SqlData::UpdateScore(SqlScoreData {id: u.borrow().id.clone(), score: u.borrow().ng.clone(), rk: u.borrow().rk, backend: backend.to_string(), rd: dev.rd, vol: dev.vol,
+        games: u.borrow().games, streak: u.borrow().streak, last_game: u.borrow().last_game}));
         //let sql = format!("UPDATE user_ng as a JOIN user as b ON a.id=b.id SET score={} WHERE b.userid='{}';", u.borrow().ng, u.borrow().id);
     //println!("sql: {}", sql);
     //let qres = conn.query(sql.clone())?;
@@ -385,124 +798,536 @@ fn get_rk(team : &Vec<Rc<RefCell<User>>>) -> Vec<i32> {
     res
 }
 
-fn settlement_ng_score(win: &Vec<Rc<RefCell<User>>>, lose: &Vec<Rc<RefCell<User>>>, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>, conn: &mut mysql::PooledConn) {
+fn get_rating(team : &Vec<Rc<RefCell<User>>>, backend: &str) -> Vec<PlayerRating> {
+    let mut res: Vec<PlayerRating> = vec![];
+    for u in team {
+        let dev = u.borrow().deviation(backend);
+        res.push(PlayerRating {score: u.borrow().ng as f32, rd: dev.rd, vol: dev.vol,
+            games: u.borrow().games, streak: u.borrow().streak});
+    }
+    res
+}
+
+fn set_rating(u: &Rc<RefCell<User>>, r: &PlayerRating, game: u32, mode_name: &str, mode: &ModeConfig, reason: String, live: bool, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>, conn: &mut mysql::PooledConn) {
+    let backend = backend_name(mode);
+    let mut ledger = SqlLedgerData {id: u.borrow().id.clone(), game: game, mode: mode_name.to_string(), time: unix_time(),
+        ng_before: u.borrow().ng, rk_before: u.borrow().rk, rd_before: u.borrow().deviation(backend).rd,
+        vol_before: u.borrow().deviation(backend).vol, tier_before: u.borrow().tier.level, reason: reason, ..Default::default()};
+    let delta = r.score.round() as i16 - u.borrow().ng;
+    u.borrow_mut().deviations.insert(backend.to_string(), Deviation {rd: r.rd, vol: r.vol});
+    u.borrow_mut().games = r.games;
+    u.borrow_mut().streak = r.streak;
+    u.borrow_mut().last_game = ledger.time;
+    user_score(u, delta, mode, live, msgtx, sender, conn);
+    ledger.ng_after = u.borrow().ng;
+    ledger.rk_after = u.borrow().rk;
+    ledger.rd_after = r.rd;
+    // ledger rows are inserts, a second writer would double them
+    if live {
+        sender.send(SqlData::Ledger(ledger));
+    }
+}
+
+// rk stays hidden during placement, so unplaced players are left off the rk board
+fn update_boards(boards: &mut Leaderboards, u: &User, season: u32, placement_games: u32) {
+    boards.update("ng", &u.region, season, &u.id, u.ng);
+    if u.games >= placement_games {
+        boards.update("rk", &u.region, season, &u.id, u.rk);
+    } else {
+        boards.remove("rk", &u.region, season, &u.id);
+    }
+}
+
+fn get_season_history(id: &String, conn: &mut mysql::PooledConn) -> Result<Vec<SeasonCell>, Error> {
+    let mut res: Vec<SeasonCell> = vec![];
+    let qres = conn.prep_exec("select h.season as season, h.ng as ng, h.rk as rk, h.tier as tier from season_history as h join user as u on u.id=h.id where u.userid=? order by h.season;", (id,))?;
+    for row in qres {
+        let a = row?.clone();
+        res.push(SeasonCell {
+            season: mysql::from_value(a.get("season").unwrap()),
+            ng: mysql::from_value(a.get("ng").unwrap()),
+            rk: mysql::from_value(a.get("rk").unwrap()),
+            tier: mysql::from_value(a.get("tier").unwrap()),
+        });
+    }
+    Ok(res)
+}
+
+fn get_rating_history(x: &RatingHistoryData, conn: &mut mysql::PooledConn) -> Result<Vec<LedgerCell>, Error> {
+    let limit = if x.limit == 0 || x.limit > 100 { 20 } else { x.limit };
+    let mut res: Vec<LedgerCell> = vec![];
+    let qres = conn.prep_exec("select l.game_id as game, l.mode as mode, l.time as time, l.ng_before as ng_before, l.ng_after as ng_after, l.ng_delta as delta, l.rk_before as rk_before, l.rk_after as rk_after, l.reason as reason from rating_ledger as l join user as u on u.id=l.id where u.userid=? and (?='' or l.mode=?) order by l.seq desc limit ?;",
+        (&x.id, &x.mode, &x.mode, limit))?;
+    for row in qres {
+        let a = row?.clone();
+        res.push(LedgerCell {
+            game: mysql::from_value(a.get("game").unwrap()),
+            mode: mysql::from_value(a.get("mode").unwrap()),
+            time: mysql::from_value(a.get("time").unwrap()),
+            ng_before: mysql::from_value(a.get("ng_before").unwrap()),
+            ng_after: mysql::from_value(a.get("ng_after").unwrap()),
+            delta: mysql::from_value(a.get("delta").unwrap()),
+            rk_before: mysql::from_value(a.get("rk_before").unwrap()),
+            rk_after: mysql::from_value(a.get("rk_after").unwrap()),
+            reason: mysql::from_value(a.get("reason").unwrap()),
+        });
+    }
+    Ok(res)
+}
+
+// results come from the rating ledger, combat stats from game_info
+fn get_profile(u: &User, config: &Config, conn: &mut mysql::PooledConn) -> Result<Value, Error> {
+    let mut wins: u32 = 0;
+    let mut losses: u32 = 0;
+    let mut draws: u32 = 0;
+    let qres = conn.prep_exec("select l.reason as reason, count(*) as cnt from rating_ledger as l join user as u on u.id=l.id where u.userid=? and l.game_id>0 and l.reason!='rollback' and l.game_id not in (select game_id from void_game) group by l.reason;", (&u.id,))?;
+    for row in qres {
+        let a = row?.clone();
+        let reason: String = mysql::from_value(a.get("reason").unwrap());
+        let cnt: u32 = mysql::from_value(a.get("cnt").unwrap());
+        if reason == "win" || reason == "place 1" {
+            wins += cnt;
+        } else if reason == "draw" {
+            draws += cnt;
+        } else {
+            losses += cnt;
+        }
+    }
+    let mut kill: u64 = 0;
+    let mut death: u64 = 0;
+    let mut assist: u64 = 0;
+    let mut damage: u64 = 0;
+    let mut played: u64 = 0;
+    let qres = conn.prep_exec("select count(*) as cnt, cast(ifnull(sum(kill_cnt), 0) as unsigned) as k, cast(ifnull(sum(death), 0) as unsigned) as d, cast(ifnull(sum(assist), 0) as unsigned) as a, cast(ifnull(sum(damage), 0) as unsigned) as dmg from game_info where userid=?;", (&u.id,))?;
+    for row in qres {
+        let a = row?.clone();
+        played = mysql::from_value(a.get("cnt").unwrap());
+        kill = mysql::from_value(a.get("k").unwrap());
+        death = mysql::from_value(a.get("d").unwrap());
+        assist = mysql::from_value(a.get("a").unwrap());
+        damage = mysql::from_value(a.get("dmg").unwrap());
+    }
+    let mut heroes: Vec<HeroCount> = vec![];
+    let qres = conn.prep_exec("select hero, count(*) as cnt from game_info where userid=? group by hero order by cnt desc limit 3;", (&u.id,))?;
+    for row in qres {
+        let a = row?.clone();
+        heroes.push(HeroCount {hero: mysql::from_value(a.get("hero").unwrap()), games: mysql::from_value(a.get("cnt").unwrap())});
+    }
+    let mut items: Vec<ItemCount> = vec![];
+    let qres = conn.prep_exec("select item, count(*) as cnt from user_equ where userid=? group by item order by cnt desc limit 5;", (&u.id,))?;
+    for row in qres {
+        let a = row?.clone();
+        items.push(ItemCount {item: mysql::from_value(a.get("item").unwrap()), games: mysql::from_value(a.get("cnt").unwrap())});
+    }
+    let rated = wins + losses + draws;
+    let mode = config.mode(DEFAULT_MODE);
+    let placed = u.games >= mode.placement_games;
+    Ok(json!({
+        "msg": "ok",
+        "id": u.id,
+        "rk": if placed { Some(u.rk) } else { None },
+        "tier": if placed { config.tiers.name(u.tier.level) } else { "".to_string() },
+        "placement": {"played": std::cmp::min(u.games, mode.placement_games), "total": mode.placement_games},
+        "games": rated,
+        "wins": wins,
+        "losses": losses,
+        "draws": draws,
+        "win_rate": if rated > 0 { wins as f32 / rated as f32 } else { 0.0 },
+        "kill": kill,
+        "death": death,
+        "assist": assist,
+        "kda": (kill + assist) as f32 / std::cmp::max(death, 1) as f32,
+        "avg_damage": if played > 0 { damage as f32 / played as f32 } else { 0.0 },
+        "heroes": heroes,
+        "items": items,
+        "seasons": get_season_history(&u.id, conn)?,
+    }))
+}
+
+fn get_match_history(id: &String, page: u32, count: u32, conn: &mut mysql::PooledConn) -> Result<Vec<MatchCell>, Error> {
+    let mut res: Vec<MatchCell> = vec![];
+    let sql = r#"select g.game_id as game, g.hero as hero, g.level as level, g.damage as damage, g.take_damage as take_damage, g.heal as heal,
+                g.kill_cnt as kill_cnt, g.death as death, g.assist as assist, l.reason as reason, l.rk_after - l.rk_before as rk_delta,
+                v.game_id is not null as void from game_info as g
+                left join rating_ledger as l on l.game_id=g.game_id and l.reason!='rollback' and l.id=(select id from user where userid=?)
+                left join void_game as v on v.game_id=g.game_id
+                where g.userid=? order by g.game_id desc limit ? offset ?;"#;
+    let qres = conn.prep_exec(sql, (id, id, count, page * count))?;
+    for row in qres {
+        let a = row?.clone();
+        res.push(MatchCell {
+            game: mysql::from_value(a.get("game").unwrap()),
+            hero: mysql::from_value(a.get("hero").unwrap()),
+            level: mysql::from_value(a.get("level").unwrap()),
+            damage: mysql::from_value(a.get("damage").unwrap()),
+            take_damage: mysql::from_value(a.get("take_damage").unwrap()),
+            heal: mysql::from_value(a.get("heal").unwrap()),
+            kill: mysql::from_value(a.get("kill_cnt").unwrap()),
+            death: mysql::from_value(a.get("death").unwrap()),
+            assist: mysql::from_value(a.get("assist").unwrap()),
+            result: mysql::from_value::<Option<String>>(a.get("reason").unwrap()).unwrap_or_default(),
+            rk_delta: mysql::from_value::<Option<i16>>(a.get("rk_delta").unwrap()).unwrap_or(0),
+            void: mysql::from_value::<u8>(a.get("void").unwrap()) != 0,
+            ..Default::default()
+        });
+    }
+    if res.len() == 0 {
+        return Ok(res);
+    }
+    let games: Vec<String> = res.iter().map(|m| m.game.to_string()).collect();
+    let mut equ: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    // game ids are numbers from game_info, only the user id needs a parameter
+    let sql = format!("select game_id, item from user_equ where userid=? and game_id in ({}) order by game_id, slot;", games.join(","));
+    let qres = conn.prep_exec(sql, (id,))?;
+    for row in qres {
+        let a = row?.clone();
+        equ.entry(mysql::from_value(a.get("game_id").unwrap())).or_default().push(mysql::from_value(a.get("item").unwrap()));
+    }
+    let mut gift: BTreeMap<u32, BTreeMap<String, u16>> = BTreeMap::new();
+    let sql = format!("select game_id, gift, value from user_gift where userid=? and game_id in ({});", games.join(","));
+    let qres = conn.prep_exec(sql, (id,))?;
+    for row in qres {
+        let a = row?.clone();
+        gift.entry(mysql::from_value(a.get("game_id").unwrap())).or_default()
+            .insert(mysql::from_value(a.get("gift").unwrap()), mysql::from_value(a.get("value").unwrap()));
+    }
+    for m in &mut res {
+        m.equ = equ.remove(&m.game).unwrap_or_default();
+        m.gift = gift.remove(&m.game).unwrap_or_default();
+    }
+    Ok(res)
+}
+
+fn get_hero_stats(hero: &String, bracket: &String, conn: &mut mysql::PooledConn) -> Result<(Vec<HeroStat>, u64), Error> {
+    let mut res: Vec<HeroStat> = vec![];
+    let mut time: u64 = 0;
+    let qres = conn.prep_exec("select * from hero_stats where (?='' or hero=?) and (?='' or bracket=?) order by bracket, games desc;",
+        (hero, hero, bracket, bracket))?;
+    for row in qres {
+        let a = row?.clone();
+        time = mysql::from_value(a.get("time").unwrap());
+        res.push(HeroStat {
+            hero: mysql::from_value(a.get("hero").unwrap()),
+            bracket: mysql::from_value(a.get("bracket").unwrap()),
+            games: mysql::from_value(a.get("games").unwrap()),
+            wins: mysql::from_value(a.get("wins").unwrap()),
+            pick_rate: mysql::from_value(a.get("pick_rate").unwrap()),
+            win_rate: mysql::from_value(a.get("win_rate").unwrap()),
+            avg_level: mysql::from_value(a.get("avg_level").unwrap()),
+            avg_damage: mysql::from_value(a.get("avg_damage").unwrap()),
+            avg_take_damage: mysql::from_value(a.get("avg_take_damage").unwrap()),
+            avg_heal: mysql::from_value(a.get("avg_heal").unwrap()),
+            avg_kill: mysql::from_value(a.get("avg_kill").unwrap()),
+            avg_death: mysql::from_value(a.get("avg_death").unwrap()),
+            avg_assist: mysql::from_value(a.get("avg_assist").unwrap()),
+        });
+    }
+    Ok((res, time))
+}
+
+// ng is the hidden rating, only the admin endpoint may read its board
+fn leaderboard_msg(boards: &Leaderboards, x: &LeaderboardQueryData, season: u32, admin: bool) -> Value {
+    let mode = if admin && x.mode == "ng" { "ng" } else { "rk" };
+    let s = if x.season == 0 { season } else { x.season };
+    let count = if x.count == 0 || x.count > 100 { 10 } else { x.count };
+    let mut entries: Vec<LeaderboardEntry> = vec![];
+    let mut total = 0;
+    let mut me = None;
+    if let Some(b) = boards.get(mode, &x.region, s) {
+        total = b.len();
+        me = b.rank(&x.id);
+        entries = if x.kind == "around" { b.around(&x.id, count / 2) } else { b.top(x.offset, count) };
+    }
+    json!({"msg": "ok", "mode": mode, "region": x.region, "season": s, "total": total, "me": me, "entries": entries})
+}
+
+fn result_reason(res: i32) -> String {
+    match res {
+        1 => "win".to_string(),
+        -1 => "loss".to_string(),
+        _ => "draw".to_string(),
+    }
+}
+
+// tiers start when placement ends, result is the sign of the streak after a game and None when
+// rk moved outside of a game, only the live instance publishes the change
+fn update_tier(u: &Rc<RefCell<User>>, tiers: &TierConfig, mode: &ModeConfig, season: u32, result: Option<i32>, live: bool, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>) -> Result<(), Error> {
+    let games = u.borrow().games;
+    if games < mode.placement_games {
+        return Ok(());
+    }
+    let rk = u.borrow().rk;
+    let (state, change) = match result {
+        Some(_) if games == mode.placement_games => (tiers.place(rk), TierChange::Placed),
+        _ => tiers.update(&u.borrow().tier, rk, result.unwrap_or(0)),
+    };
+    if change == TierChange::None {
+        return Ok(());
+    }
+    u.borrow_mut().tier = state.clone();
+    if !live {
+        return Ok(());
+    }
+    msgtx.try_send(MqttMsg{topic:format!("member/{}/res/tier", u.borrow().id), 
+        msg: json!({"msg": "ok", "tier": tiers.name(state.level), "change": change, "season": season,
+            "in_series": state.in_series, "series_wins": state.series_wins, "series_losses": state.series_losses}).to_string()})?;
+    sender.send(SqlData::UpdateTier(SqlTierData {id: u.borrow().id.clone(), season: season, tier: state}));
+    Ok(())
+}
+
+fn settlement_ng_score(win: &Vec<Rc<RefCell<User>>>, lose: &Vec<Rc<RefCell<User>>>, draw: bool, game: u32, mode_name: &str, mode: &ModeConfig, live: bool, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>, conn: &mut mysql::PooledConn) -> Result<(), Error> {
     if win.len() == 0 || lose.len() == 0 {
-        return;
+        return Ok(());
     }
-    let win_ng = get_ng(win);
-    let lose_ng = get_ng(lose);
-    let elo = EloRank {k:20.0};
-    let (rw, rl) = elo.compute_elo_team(&win_ng, &lose_ng);
+    let rating = create_rating(mode)?;
+    let backend = backend_name(mode);
+    let res = settle(&*rating, &vec![get_rating(win, backend), get_rating(lose, backend)], false, draw);
     println!("Game Over");
-    for (i, u) in win.iter().enumerate() {
-        user_score(u, (rw[i]-win_ng[i]) as i16, msgtx, sender, conn);
+    for (t, (rt, r)) in [win, lose].iter().zip(res.iter()) {
+        for (i, u) in t.iter().enumerate() {
+            set_rating(u, &rt[i], game, mode_name, mode, result_reason(*r), live, msgtx, sender, conn);
+        }
     }
-    for (i, u) in lose.iter().enumerate() {
-        user_score(u, (rl[i]-lose_ng[i]) as i16, msgtx, sender, conn);
+    Ok(())
+}
+
+fn settlement_placement(teams: &Vec<Vec<Rc<RefCell<User>>>>, game: u32, mode_name: &str, mode: &ModeConfig, live: bool, msgtx: &Sender<MqttMsg>, sender: &Sender<SqlData>, conn: &mut mysql::PooledConn) -> Result<(), Error> {
+    if teams.len() < 2 || teams.iter().any(|t| t.len() == 0) {
+        return Ok(());
+    }
+    let rating = create_rating(mode)?;
+    let res = settle(&*rating, &teams.iter().map(|t| get_rating(t, backend_name(mode))).collect(), true, false);
+    println!("Game Over");
+    for (i, t) in teams.iter().enumerate() {
+        for (j, u) in t.iter().enumerate() {
+            set_rating(u, &res[i].0[j], game, mode_name, mode, format!("place {}", i+1), live, msgtx, sender, conn);
+        }
     }
+    Ok(())
 }
 
-pub fn HandleSqlRequest(pool: mysql::Pool)
+// tables created by an older version keep their old columns, create table if not exists skips them.
+// Returns true when the column was added.
+fn add_column(conn: &mut mysql::PooledConn, table: &str, column: &str, definition: &str) -> Result<bool, Error> {
+    let missing = conn.prep_exec("select column_name from information_schema.columns where table_schema=database() and table_name=? and column_name=?;", (table, column))?.count() == 0;
+    if missing {
+        conn.query(format!("alter table {} add column {} {};", table, column, definition))?;
+    }
+    Ok(missing)
+}
+
+// users that logged in for the first time since the last tick
+fn insert_new_users(users: &Vec<String>, ratings: &Vec<PlayerRating>, regions: &Vec<String>, conn: &mut mysql::PooledConn) -> Result<(), Error> {
+    let len = users.len();
+    let mut insert_str: String = "insert into user (userid, name, status) values".to_string();
+    for (i, u) in users.iter().enumerate() {
+        let mut new_user = format!(" ('{}', 'default name', 'online')", u);
+        insert_str += &new_user;
+        if i < len-1 {
+            insert_str += ",";
+        }
+    }
+    insert_str += ";";
+    //println!("{}", insert_str);
+    {
+        conn.query(insert_str.clone())?;
+    }
+
+    let sql = format!("select id from user where userid='{}';", users[0]);
+    //println!("sql: {}", sql);
+    let qres = conn.query(sql.clone())?;
+    let mut id = 0;
+    let mut name: String = "".to_owned();
+    for row in qres {
+        let a = row?.clone();
+        id = mysql::from_value(a.get("id").unwrap());
+    }
+
+    let mut insert_rk: String = "insert into user_rank (id, score) values".to_string();
+    for i in 0..len {
+        let mut new_user = format!(" ({}, 1000)", id+i);
+        insert_rk += & new_user;
+        if i < len-1 {
+            insert_rk += ",";
+        }
+    }
+    insert_rk += ";";
+    //println!("{}", insert_rk);
+    {
+        conn.query(insert_rk.clone())?;
+    }
+
+    let mut insert_ng: String = "insert into user_ng (id, score) values".to_string();
+    for i in 0..len {
+        let mut new_user = format!(" ({}, {})", id+i, ratings[i].score.round());
+        insert_ng += &new_user;
+        if i < len-1 {
+            insert_ng += ",";
+        }
+    }
+    insert_ng += ";";
+    //println!("{}", insert_ng);
+    {
+        conn.query(insert_ng.clone())?;
+    }
+
+    let mut insert_rating: String = "insert into user_rating (id, rd, vol) values".to_string();
+    for i in 0..len {
+        let mut new_user = format!(" ({}, {}, {})", id+i, ratings[i].rd, ratings[i].vol);
+        insert_rating += &new_user;
+        if i < len-1 {
+            insert_rating += ",";
+        }
+    }
+    insert_rating += ";";
+    {
+        conn.query(insert_rating.clone())?;
+    }
+
+    // a bad region only loses that row instead of the whole batch
+    for i in 0..len {
+        if regions[i] != "" {
+            if let Err(e) = conn.prep_exec("insert into user_region (id, region) values (?, ?);", (id+i, &regions[i])) {
+                error!("user_region {}: {}", users[i], e);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn HandleSqlRequest(pool: mysql::Pool, config: Config)
     -> Result<Sender<SqlData>, Error> {
         let (tx1, rx1): (Sender<SqlData>, Receiver<SqlData>) = bounded(10000);
         let start = Instant::now();
         let update1000ms = tick(Duration::from_millis(2000));
         let mut NewUsers: Vec<String> = Vec::new();
+        let mut NewRatings: Vec<PlayerRating> = Vec::new();
+        let mut NewRegions: Vec<String> = Vec::new();
         let mut len = 0;
         let mut UpdateInfo: Vec<SqlGameInfoData> = Vec::new();
         let mut info_len = 0; 
 
+        // created before the room thread starts reading them
+        let mut conn = pool.get_conn()?;
+        conn.query("create table if not exists user_rating (id int primary key, rd float not null, vol float not null, games int not null default 0, streak int not null default 0, last_game bigint not null default 0, last_decay bigint not null default 0, decay_pending int not null default 0);")?;
+        // rows older than the games count belong to players who are already ranked
+        if add_column(&mut conn, "user_rating", "games", "int not null default 0")? {
+            conn.query(format!("update user_rating set games={};", config.mode(DEFAULT_MODE).placement_games))?;
+        }
+        add_column(&mut conn, "user_rating", "streak", "int not null default 0")?;
+        add_column(&mut conn, "user_rating", "last_game", "bigint not null default 0")?;
+        add_column(&mut conn, "user_rating", "last_decay", "bigint not null default 0")?;
+        add_column(&mut conn, "user_rating", "decay_pending", "int not null default 0")?;
+        // rd and vol in user_rating were shared by every backend, they only seed the default mode's backend
+        let qres: mysql::QueryResult = conn.query("select table_name from information_schema.tables where table_schema=database() and table_name='user_deviation';")?;
+        let seed = qres.count() == 0;
+        conn.query("create table if not exists user_deviation (id int not null, backend varchar(16) not null, rd float not null, vol float not null, primary key (id, backend));")?;
+        if seed {
+            conn.prep_exec("insert ignore into user_deviation (id, backend, rd, vol) select id, ?, rd, vol from user_rating;", (backend_name(config.mode(DEFAULT_MODE)),))?;
+        }
+        conn.query("create table if not exists user_tier (id int not null, season int not null, level int not null, in_series tinyint not null, series_wins int not null, series_losses int not null, primary key (id, season));")?;
+        conn.query("create table if not exists rating_ledger (seq bigint auto_increment primary key, id int not null, game_id int not null, mode varchar(16) not null, time bigint not null, ng_before int not null, ng_after int not null, ng_delta int not null, rk_before int not null, rk_after int not null, rd_before float not null, rd_after float not null, vol_before float null, tier_before int null, reason varchar(16) not null, index (id), index (game_id));")?;
+        // rollback restores vol and tier too, older rows leave them as they are
+        add_column(&mut conn, "rating_ledger", "vol_before", "float null")?;
+        add_column(&mut conn, "rating_ledger", "tier_before", "int null")?;
+        // both instances used to write every game's rows, keep the first of each
+        conn.query("delete l1 from rating_ledger as l1 join rating_ledger as l2 on l1.id=l2.id and l1.game_id=l2.game_id and l1.reason=l2.reason and l1.seq>l2.seq where l1.game_id>0;")?;
+        conn.query("create table if not exists user_region (id int primary key, region varchar(16) not null);")?;
+        conn.query("create table if not exists void_game (game_id int primary key, time bigint not null, admin varchar(64) not null);")?;
+        conn.query("create table if not exists decay_log (id int not null, time bigint not null, rk_before int not null, rk_after int not null, rd_before float not null, rd_after float not null, index (id));")?;
+        conn.query("create table if not exists season (id int primary key, start_time datetime not null, end_time datetime null);")?;
+        // stats were smallint and overflowed in long games
+        let qres: mysql::QueryResult = conn.query("select data_type from information_schema.columns where table_schema=database() and table_name='game_info' and column_name='damage';")?;
+        let mut widen = false;
+        for row in qres {
+            let a = row?.clone();
+            let t: String = mysql::from_value(a.get("data_type").unwrap());
+            widen = t != "int";
+        }
+        if widen {
+            conn.query("alter table game_info modify damage int unsigned not null, modify take_damage int unsigned not null, modify heal int unsigned not null, modify kill_cnt int unsigned not null, modify death int unsigned not null, modify assist int unsigned not null;")?;
+        }
+        // equipment and gifts used to be flattened into user_info, they are copied over once
+        let qres: mysql::QueryResult = conn.query("select table_name from information_schema.tables where table_schema=database() and table_name in ('user_equ', 'user_info');")?;
+        let mut tables: Vec<String> = vec![];
+        for row in qres {
+            let a = row?.clone();
+            tables.push(mysql::from_value(a.get("table_name").unwrap()));
+        }
+        let migrate = tables.contains(&"user_info".to_string()) && !tables.contains(&"user_equ".to_string());
+        conn.query("create table if not exists user_equ (userid varchar(64) not null, game_id int not null, slot int not null, item varchar(64) not null, primary key (userid, game_id, slot));")?;
+        conn.query("create table if not exists user_gift (userid varchar(64) not null, game_id int not null, gift varchar(64) not null, value int not null, primary key (userid, game_id, gift));")?;
+        if migrate {
+            let mut rows: Vec<(String, u32, String)> = vec![];
+            let qres: mysql::QueryResult = conn.query("select cast(userid as char) as userid, game_id, equ from user_info;")?;
+            for row in qres {
+                let a = row?.clone();
+                rows.push((mysql::from_value(a.get("userid").unwrap()), mysql::from_value(a.get("game_id").unwrap()), mysql::from_value(a.get("equ").unwrap())));
+            }
+            for (userid, game, equ) in &rows {
+                for (slot, item) in equ.split(", ").filter(|e| *e != "").enumerate() {
+                    conn.prep_exec("insert ignore into user_equ (userid, game_id, slot, item) values (?, ?, ?, ?);", (userid, *game, slot, item))?;
+                }
+            }
+            // the old a-e gift columns keep their names as map keys
+            for g in &["a", "b", "c", "d", "e"] {
+                conn.query(format!("insert ignore into user_gift (userid, game_id, gift, value) select userid, game_id, '{}', gift_{} from user_info where gift_{} != 0;",
+                    g, g.to_uppercase(), g.to_uppercase()))?;
+            }
+        }
+        conn.query("create table if not exists hero_stats (hero varchar(64) not null, bracket varchar(32) not null, games int not null, wins int not null, pick_rate float not null, win_rate float not null, avg_level float not null, avg_damage float not null, avg_take_damage float not null, avg_heal float not null, avg_kill float not null, avg_death float not null, avg_assist float not null, time bigint not null, primary key (hero, bracket));")?;
+        conn.query("create table if not exists season_history (id int not null, season int not null, ng int not null, rk int not null, level int not null, tier varchar(32) not null, primary key (id, season));")?;
+
         thread::spawn(move || -> Result<(), Error> {
-            let mut conn = pool.get_conn()?;
             loop{
                 select! {
 
                     recv(update1000ms) -> _ => {
+                        let batch_start = Instant::now();
+                        let batched = len > 0 || info_len > 0;
                         if len > 0 {
-                            // insert new user in NewUsers
-                            let mut insert_str: String = "insert into user (userid, name, status) values".to_string();
-                            for (i, u) in NewUsers.iter().enumerate() {
-                                let mut new_user = format!(" ('{}', 'default name', 'online')", u);
-                                insert_str += &new_user;
-                                if i < len-1 {
-                                    insert_str += ",";
-                                }
-                            }
-                            insert_str += ";";
-                            //println!("{}", insert_str);
-                            {
-                                conn.query(insert_str.clone())?;
-                            }
-
-                            let sql = format!("select id from user where userid='{}';", NewUsers[0]);
-                            //println!("sql: {}", sql);
-                            let qres = conn.query(sql.clone())?;
-                            let mut id = 0;
-                            let mut name: String = "".to_owned();
-                            for row in qres {
-                                let a = row?.clone();
-                                id = mysql::from_value(a.get("id").unwrap());
-                            }
-
-                            let mut insert_rk: String = "insert into user_rank (id, score) values".to_string();
-                            for i in 0..len {
-                                let mut new_user = format!(" ({}, 1000)", id+i);
-                                insert_rk += & new_user;
-                                if i < len-1 {
-                                    insert_rk += ",";
-                                }
-                            }
-                            insert_rk += ";";
-                            //println!("{}", insert_rk);
-                            {
-                                conn.query(insert_rk.clone())?;
-                            }
-
-                            let mut insert_ng: String = "insert into user_ng (id, score) values".to_string();
-                            for i in 0..len {
-                                let mut new_user = format!(" ({}, 1000)", id+i);
-                                insert_ng += &new_user;
-                                if i < len-1 {
-                                    insert_ng += ",";
-                                }
-                            }
-                            insert_ng += ";";
-                            //println!("{}", insert_ng);
-                            {
-                                conn.query(insert_ng.clone())?;
-                            }
-                            
+                            insert_new_users(&NewUsers, &NewRatings, &NewRegions, &mut conn)?;
                             len = 0;
                             NewUsers.clear();
+                            NewRatings.clear();
+                            NewRegions.clear();
                         }
 
                         if info_len > 0 {
                             
                             let mut insert_info: String = "insert into game_info (userid, game_id, hero, level, damage, take_damage, heal, kill_cnt, death, assist) values".to_string();
-                            let mut user_info: String = "insert into user_info (userid, game_id, equ, gift_A, gift_B, gift_C, gift_D, gift_E) values".to_string();
                             for (i, info) in UpdateInfo.iter().enumerate() {
                                 let mut new_user = format!(" ({}, {}, '{}', {}, {}, {}, {}, {}, {}, {})", info.id, info.game, info.hero, info.level, info.damage, info.take_damage, info.heal, info.kill, info.death, info.assist);
                                 insert_info += &new_user;
-                                let mut new_user1 = format!(" ({}, {}, '{}', {}, {}, {}, {}, {})", info.id, info.game, info.equ, info.gift.a, info.gift.b, info.gift.c, info.gift.d, info.gift.e);
-                                user_info += &new_user1;
                                 if i < info_len-1 {
                                     insert_info += ",";
-                                    user_info += ",";
                                 }
                             }
                             insert_info += ";";
-                            user_info += ";";
                             {
                                 conn.query(insert_info.clone())?;
                             }
-                            {
-                                conn.query(user_info.clone())?;
+                            // item and gift names come from the game server, a bad one only loses its own row
+                            for info in &UpdateInfo {
+                                for (slot, item) in info.equ.iter().enumerate() {
+                                    if let Err(e) = conn.prep_exec("insert ignore into user_equ (userid, game_id, slot, item) values (?, ?, ?, ?);", (&info.id, info.game, slot, item)) {
+                                        error!("user_equ {} {}: {}", info.id, info.game, e);
+                                    }
+                                }
+                                for (name, value) in &info.gift {
+                                    if let Err(e) = conn.prep_exec("insert ignore into user_gift (userid, game_id, gift, value) values (?, ?, ?, ?);", (&info.id, info.game, name, *value)) {
+                                        error!("user_gift {} {}: {}", info.id, info.game, e);
+                                    }
+                                }
                             }
                             
 
                             info_len = 0;
                             UpdateInfo.clear();
                         }
+                        if batched {
+                            metrics::SQL_BATCH.observe(Instant::now().duration_since(batch_start).as_secs_f64());
+                        }
 
                     }
 
@@ -513,13 +1338,90 @@ pub fn HandleSqlRequest(pool: mysql::Pool)
                                     
                                     SqlData::Login(x) => {                                       
                                         NewUsers.push(x.id.clone());
+                                        NewRatings.push(x.rating);
+                                        NewRegions.push(x.region.clone());
                                         len+=1;                                        
                                     }
                                     SqlData::UpdateScore(x) => {
                                         //println!("in");
                                         let sql = format!("UPDATE user_ng as a JOIN user as b ON a.id=b.id SET score={} WHERE b.userid='{}';", x.score, x.id);
                                         //println!("sql: {}", sql);
-                                        let qres = conn.query(sql.clone())?;
+                                        conn.query(sql.clone())?;
+                                        let sql = format!("UPDATE user_rank as a JOIN user as b ON a.id=b.id SET score={} WHERE b.userid='{}';", x.rk, x.id);
+                                        conn.query(sql.clone())?;
+                                        let sql = format!("insert into user_rating (id, rd, vol, games, streak, last_game) select id, {}, {}, {}, {}, {} from user where userid='{}' on duplicate key update games={}, streak={}, last_game={};",
+                                            x.rd, x.vol, x.games, x.streak, x.last_game, x.id, x.games, x.streak, x.last_game);
+                                        conn.query(sql.clone())?;
+                                        conn.prep_exec("insert into user_deviation (id, backend, rd, vol) select id, ?, ?, ? from user where userid=? on duplicate key update rd=values(rd), vol=values(vol);",
+                                            (&x.backend, x.rd, x.vol, &x.id))?;
+                                    }
+                                    SqlData::UpdateTier(x) => {
+                                        let t = &x.tier;
+                                        let sql = format!("insert into user_tier (id, season, level, in_series, series_wins, series_losses) select id, {}, {}, {}, {}, {} from user where userid='{}' on duplicate key update level={}, in_series={}, series_wins={}, series_losses={};",
+                                            x.season, t.level, t.in_series as u8, t.series_wins, t.series_losses, x.id, t.level, t.in_series as u8, t.series_wins, t.series_losses);
+                                        conn.query(sql.clone())?;
+                                    }
+                                    SqlData::Decay(x) => {
+                                        conn.query(format!("insert into decay_log (id, time, rk_before, rk_after, rd_before, rd_after) select id, {}, {}, {}, {}, {} from user where userid='{}';",
+                                            x.time, x.rk_before, x.rk_after, x.rd_before, x.rd_after, x.id))?;
+                                        conn.query(format!("UPDATE user_rank as a JOIN user as b ON a.id=b.id SET score={} WHERE b.userid='{}';", x.rk_after, x.id))?;
+                                        conn.query(format!("UPDATE user_rating as a JOIN user as b ON a.id=b.id SET last_decay={}, decay_pending={} WHERE b.userid='{}';",
+                                            x.time, x.pending, x.id))?;
+                                        for (backend, d) in &x.deviations {
+                                            conn.prep_exec("insert into user_deviation (id, backend, rd, vol) select id, ?, ?, ? from user where userid=? on duplicate key update rd=values(rd), vol=values(vol);",
+                                                (backend, d.rd, d.vol, &x.id))?;
+                                        }
+                                    }
+                                    SqlData::Ledger(x) => {
+                                        conn.query(format!("insert into rating_ledger (id, game_id, mode, time, ng_before, ng_after, ng_delta, rk_before, rk_after, rd_before, rd_after, vol_before, tier_before, reason) select id, {}, '{}', {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, '{}' from user where userid='{}';",
+                                            x.game, x.mode, x.time, x.ng_before, x.ng_after, x.ng_after - x.ng_before, x.rk_before, x.rk_after, x.rd_before, x.rd_after, x.vol_before, x.tier_before, x.reason, x.id))?;
+                                    }
+                                    SqlData::UpdateRegion(x) => {
+                                        conn.prep_exec("insert into user_region (id, region) select id, ? from user where userid=? on duplicate key update region=values(region);", (&x.region, &x.id))?;
+                                    }
+                                    SqlData::VoidGame(x) => {
+                                        conn.query(format!("insert into void_game (game_id, time, admin) values ({}, {}, '{}');", x.game, x.time, x.admin))?;
+                                    }
+                                    SqlData::DecayNotified(id) => {
+                                        conn.query(format!("UPDATE user_rating as a JOIN user as b ON a.id=b.id SET decay_pending=0 WHERE b.userid='{}';", id))?;
+                                    }
+                                    SqlData::SeasonEnd(x) => {
+                                        // pending first logins have no id yet, write them before looking players up
+                                        if len > 0 {
+                                            insert_new_users(&NewUsers, &NewRatings, &NewRegions, &mut conn)?;
+                                            len = 0;
+                                            NewUsers.clear();
+                                            NewRatings.clear();
+                                            NewRegions.clear();
+                                        }
+                                        for u in &x.users {
+                                            if let Err(e) = conn.prep_exec("insert into season_history (id, season, ng, rk, level, tier) select id, ?, ?, ?, ?, ? from user where userid=?;",
+                                                (x.season, u.ng, u.rk, u.level, &u.tier, &u.id)) {
+                                                error!("season_history {}: {}", u.id, e);
+                                            }
+                                        }
+                                        conn.query(format!("update season set end_time=now() where id={};", x.season))?;
+                                    }
+                                    SqlData::SeasonStart(x) => {
+                                        conn.query(format!("insert into season (id, start_time) values ({}, now());", x.season))?;
+                                        let mean = x.reset.reset_mean;
+                                        let keep = x.reset.reset_keep;
+                                        conn.query(format!("update user_ng set score=round({} + (score - {}) * {});", mean, mean, keep))?;
+                                        conn.query(format!("update user_rank set score=round({} + (score - {}) * {});", mean, mean, keep))?;
+                                        // pending first logins have no id yet, write them before looking players up
+                                        if len > 0 {
+                                            insert_new_users(&NewUsers, &NewRatings, &NewRegions, &mut conn)?;
+                                            len = 0;
+                                            NewUsers.clear();
+                                            NewRatings.clear();
+                                            NewRegions.clear();
+                                        }
+                                        for (id, t) in &x.tiers {
+                                            if let Err(e) = conn.prep_exec("insert into user_tier (id, season, level, in_series, series_wins, series_losses) select id, ?, ?, 0, 0, 0 from user where userid=?;",
+                                                (x.season, t.level, id)) {
+                                                error!("user_tier {}: {}", id, e);
+                                            }
+                                        }
                                     }
                                     SqlData::UpdateGameInfo(x) => {
                                         UpdateInfo.push(x.clone());
@@ -536,11 +1438,126 @@ pub fn HandleSqlRequest(pool: mysql::Pool)
                     }
                 }
             }
-        });
-    Ok(tx1)
+        });
+    Ok(tx1)
+}
+
+// rebuilds hero_stats from the current season's rated games, only started on the live instance
+pub fn HandleHeroStats(pool: mysql::Pool, config: Config) -> Result<(), Error> {
+    if config.hero_stats.interval_secs == 0 {
+        return Ok(());
+    }
+    thread::spawn(move || -> Result<(), Error> {
+        let mut conn = pool.get_conn()?;
+        loop {
+            let start = Instant::now();
+            let mut run = || -> Result<usize, Error> {
+                let mut games: Vec<HeroGame> = vec![];
+                let sql = r#"select g.hero as hero, l.rk_before as rk, l.reason as reason, g.level as level, g.damage as damage, g.take_damage as take_damage,
+                            g.heal as heal, g.kill_cnt as kill_cnt, g.death as death, g.assist as assist from game_info as g
+                            join user as u on u.userid=g.userid
+                            join rating_ledger as l on l.id=u.id and l.game_id=g.game_id and l.reason!='rollback'
+                            where g.game_id not in (select game_id from void_game)
+                            and l.time >= coalesce((select unix_timestamp(start_time) from season order by id desc limit 1), 0);"#;
+                let qres: mysql::QueryResult = conn.query(sql)?;
+                for row in qres {
+                    let a = row?.clone();
+                    let reason: String = mysql::from_value(a.get("reason").unwrap());
+                    games.push(HeroGame {
+                        hero: mysql::from_value(a.get("hero").unwrap()),
+                        bracket: config.tiers.tier_name(mysql::from_value(a.get("rk").unwrap())),
+                        win: reason == "win" || reason == "place 1",
+                        level: mysql::from_value(a.get("level").unwrap()),
+                        damage: mysql::from_value(a.get("damage").unwrap()),
+                        take_damage: mysql::from_value(a.get("take_damage").unwrap()),
+                        heal: mysql::from_value(a.get("heal").unwrap()),
+                        kill: mysql::from_value(a.get("kill_cnt").unwrap()),
+                        death: mysql::from_value(a.get("death").unwrap()),
+                        assist: mysql::from_value(a.get("assist").unwrap()),
+                    });
+                }
+                let stats = aggregate(&games);
+                let now = unix_time();
+                // readers never see a half written table, heroes missing this season drop out
+                let mut t = conn.start_transaction(false, None, None)?;
+                t.query("delete from hero_stats;")?;
+                for s in &stats {
+                    let row: Vec<mysql::Value> = vec![s.hero.clone().into(), s.bracket.clone().into(), s.games.into(), s.wins.into(),
+                        s.pick_rate.into(), s.win_rate.into(), s.avg_level.into(), s.avg_damage.into(), s.avg_take_damage.into(),
+                        s.avg_heal.into(), s.avg_kill.into(), s.avg_death.into(), s.avg_assist.into(), now.into()];
+                    t.prep_exec("insert into hero_stats (hero, bracket, games, wins, pick_rate, win_rate, avg_level, avg_damage, avg_take_damage, avg_heal, avg_kill, avg_death, avg_assist, time) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);", row)?;
+                }
+                t.commit()?;
+                Ok(stats.len())
+            };
+            match run() {
+                Ok(n) => info!("hero stats: {} rows, time: {:?}", n, Instant::now().duration_since(start)),
+                Err(e) => error!("hero stats: {}", e),
+            }
+            thread::sleep(Duration::from_secs(config.hero_stats.interval_secs));
+        }
+    });
+    Ok(())
+}
+
+// player queries that only read the database, answered by HandleReadRequest
+pub enum ReadData {
+    SeasonHistory(SeasonHistoryData, u32),
+    RatingHistory(RatingHistoryData),
+    // snapshot of the user taken by the room thread
+    Profile(User),
+    MatchHistory(MatchHistoryData),
+    HeroStats(HeroStatsQueryData),
+}
+
+impl ReadData {
+    fn topic(&self) -> String {
+        match self {
+            ReadData::SeasonHistory(x, _) => format!("member/{}/res/season_history", x.id),
+            ReadData::RatingHistory(x) => format!("member/{}/res/rating_history", x.id),
+            ReadData::Profile(u) => format!("member/{}/res/profile", u.id),
+            ReadData::MatchHistory(x) => format!("member/{}/res/history", x.id),
+            ReadData::HeroStats(x) => format!("stats/{}/res/hero", x.id),
+        }
+    }
+}
+
+fn read_reply(x: &ReadData, config: &Config, conn: &mut mysql::PooledConn) -> Result<Value, Error> {
+    match x {
+        ReadData::SeasonHistory(x, season) => Ok(json!({"msg": "ok", "season": season, "history": get_season_history(&x.id, conn)?})),
+        ReadData::RatingHistory(x) => Ok(json!({"msg": "ok", "history": get_rating_history(x, conn)?})),
+        ReadData::Profile(u) => get_profile(u, config, conn),
+        ReadData::MatchHistory(x) => {
+            let count = if x.count == 0 || x.count > 50 { 10 } else { x.count };
+            Ok(json!({"msg": "ok", "page": x.page, "count": count, "games": get_match_history(&x.id, x.page, count, conn)?}))
+        },
+        ReadData::HeroStats(x) => {
+            let (res, time) = get_hero_stats(&x.hero, &x.bracket, conn)?;
+            Ok(json!({"msg": "ok", "time": time, "stats": res}))
+        },
+    }
 }
 
-pub fn HandleQueueRequest(msgtx: Sender<MqttMsg>, sender: Sender<RoomEventData>)
+// keeps slow reads off the room thread, a failed query is answered and logged
+pub fn HandleReadRequest(pool: mysql::Pool, config: Config, msgtx: Sender<MqttMsg>) -> Result<Sender<ReadData>, Error> {
+    let (tx, rx): (Sender<ReadData>, Receiver<ReadData>) = bounded(1000);
+    thread::spawn(move || {
+        for x in rx.iter() {
+            let res = pool.get_conn().map_err(Error::from).and_then(|mut conn| read_reply(&x, &config, &mut conn));
+            let msg = match res {
+                Ok(v) => v.to_string(),
+                Err(e) => {
+                    error!("{}: {}", x.topic(), e);
+                    json!({"msg": "fail", "reason": "query failed"}).to_string()
+                },
+            };
+            msgtx.try_send(MqttMsg{topic: x.topic(), msg: msg});
+        }
+    });
+    Ok(tx)
+}
+
+pub fn HandleQueueRequest(msgtx: Sender<MqttMsg>, sender: Sender<RoomEventData>, config: Config)
     -> Result<Sender<QueueData>, Error> {
     let (tx, rx):(Sender<QueueData>, Receiver<QueueData>) = bounded(10000);
     let start = Instant::now();
@@ -548,159 +1565,41 @@ pub fn HandleQueueRequest(msgtx: Sender<MqttMsg>, sender: Sender<RoomEventData>)
         
     thread::spawn(move || -> Result<(), Error> {
         let mut QueueRoom: BTreeMap<u32, Rc<RefCell<QueueRoomData>>> = BTreeMap::new();
-        let mut ReadyGroups: BTreeMap<u32, Rc<RefCell<ReadyGroupData>>> = BTreeMap::new();
+        let mut Matchmakers: BTreeMap<String, Box<dyn Matchmaker>> = BTreeMap::new();
+        // when each room entered the queue, for the wait time histogram
+        let mut QueueStart: BTreeMap<u32, Instant> = BTreeMap::new();
         
-        let mut group_id = 0;
         loop {
             select! {
                 recv(update) -> _ => {
                     
                     let mut new_now = Instant::now();
-                    if QueueRoom.len() >= MATCH_SIZE {
-                        let mut g: ReadyGroupData = Default::default();
-                        let mut tq: Vec<Rc<RefCell<QueueRoomData>>> = vec![];
-                        let mut id: Vec<u32> = vec![];
-                        let mut new_now = Instant::now();
-                        tq = QueueRoom.iter().map(|x|Rc::clone(x.1)).collect();
-                        //println!("Collect Time: {:?}",Instant::now().duration_since(new_now));
-                        //tq.sort_by_key(|x| x.borrow().avg_rk);
-                        
-                        let mut new_now = Instant::now();
-                        tq.sort_by_key(|x| x.borrow().avg_ng);
-                        //println!("Sort Time: {:?}",Instant::now().duration_since(new_now));
-                        let mut new_now1 = Instant::now();
-                        for (k, v) in &mut QueueRoom {
-                            
-                            
-
-                            if g.user_len > 0 && g.user_len < TEAM_SIZE && (g.avg_ng + v.borrow().queue_cnt*SCORE_INTERVAL) < v.borrow().avg_ng {
-                                for r in g.rid {
-                                    id.push(r);
-                                }
-                                g = Default::default();
-                                g.rid.push(v.borrow().rid);
-                                let mut ng = (g.avg_ng * g.user_len + v.borrow().avg_ng * v.borrow().user_len) as i16 / (g.user_len + v.borrow().user_len) as i16;
-                                g.avg_ng = ng;
-                                g.user_len += v.borrow().user_len;
-                                v.borrow_mut().ready = 1;
-                                v.borrow_mut().gid = group_id + 1;
-                                v.borrow_mut().queue_cnt += 1;
-                                
-                            }
-
-                            if v.borrow().ready == 0 &&
-                                v.borrow().user_len as i16 + g.user_len <= TEAM_SIZE {
-                                
-                                let Difference: i16 = i16::abs(v.borrow().avg_ng - g.avg_ng);
-                                if g.avg_ng == 0 || Difference <= SCORE_INTERVAL * v.borrow().queue_cnt {
-                                    g.rid.push(v.borrow().rid);
-                                    let mut ng ;
-                                    if (g.user_len + v.borrow().user_len > 0){
-                                        ng = (g.avg_ng * g.user_len + v.borrow().avg_ng * v.borrow().user_len) as i16 / (g.user_len + v.borrow().user_len) as i16;
-                                    } else {
-                                        g = Default::default();
-                                        continue;
-                                    }
-                                    g.avg_ng = ng;
-                                    g.user_len += v.borrow().user_len;
-                                    v.borrow_mut().ready = 1;
-                                    v.borrow_mut().gid = group_id + 1;
-                                }
-                                else {
-                                    v.borrow_mut().queue_cnt += 1;
-                                }
-                            }
-                            if g.user_len == TEAM_SIZE {
-                                //println!("match team_size!");
-                                group_id += 1;
-                                //info!("new group_id: {}", group_id);
-                                g.gid = group_id;
-                                
-                                g.queue_cnt = 1;
-                                ReadyGroups.insert(group_id, Rc::new(RefCell::new(g.clone())));
-                                
-                                g = Default::default();
-                            }
-
-                        }
-                        //println!("Time 2: {:?}",Instant::now().duration_since(new_now1));
-                        if g.user_len < TEAM_SIZE {
-                            for r in g.rid {
-                                let mut room = QueueRoom.get(&r);
-                                if let Some(room) = room {
-                                    room.borrow_mut().ready = 0;
-                                    room.borrow_mut().gid = 0;
-                                }
-                            }
-                            for r in id {
-                                let mut room = QueueRoom.get(&r);
-                                if let Some(room) = room {
-                                    room.borrow_mut().ready = 0;
-                                    room.borrow_mut().gid = 0;
-                                }
-                            }
-                        }
+                    let mut ModeRooms: BTreeMap<String, BTreeMap<u32, Rc<RefCell<QueueRoomData>>>> = BTreeMap::new();
+                    for (rid, r) in &QueueRoom {
+                        ModeRooms.entry(r.borrow().mode.clone())
+                            .or_insert(BTreeMap::new())
+                            .insert(*rid, Rc::clone(r));
                     }
-
-                    if ReadyGroups.len() >= MATCH_SIZE {
-                        let mut fg: ReadyGameData = Default::default();
-                        //let mut prestart = false;
-                        let mut total_ng: i16 = 0;
-                        let mut rm_ids: Vec<u32> = vec![];
-                        //println!("ReadyGroup!! {}", ReadyGroups.len());
-                        let mut new_now2 = Instant::now();
-                        for (id, rg) in &mut ReadyGroups {
-                            
-                            
-
-                            if rg.borrow().game_status == 0 && fg.team_len < MATCH_SIZE {
-                                if total_ng == 0 {
-                                    total_ng += rg.borrow().avg_ng as i16;
-                                    fg.group.push(rg.borrow().rid.clone());
-                                    fg.gid.push(*id);
-                                    fg.team_len += 1;
-                                    continue;
-                                }
-                                
-                                let mut difference = 0;
-                                if fg.team_len > 0 {
-                                    difference = i16::abs(rg.borrow().avg_ng as i16 - total_ng/fg.team_len as i16);
-                                }
-                                if difference <= SCORE_INTERVAL * rg.borrow().queue_cnt {
-                                    total_ng += rg.borrow().avg_ng as i16;
-                                    fg.group.push(rg.borrow().rid.clone());
-                                    fg.team_len += 1;
-                                    fg.gid.push(*id);
-                                }
-                                else {
-                                    rg.borrow_mut().queue_cnt += 1;
-                                }
-                            }
-                            if fg.team_len == MATCH_SIZE {
-                                sender.send(RoomEventData::UpdateGame(PreGameData{rid: fg.group.clone()}));
-                                for id in fg.gid {
-                                    rm_ids.push(id);
-                                }
-                                fg = Default::default();
-                            }
-                        }
-                        
-                        for id in rm_ids {
-                            let rg = ReadyGroups.remove(&id);
-                            if let Some(rg) = rg {
-                                for rid in &rg.borrow().rid {
-                                    
-                                    QueueRoom.remove(&rid);
-                                    
+                    metrics::set_queue_rooms(&ModeRooms.iter().map(|(m, r)| (m.clone(), r.len())).collect());
+                    metrics::QUEUE_CHANNEL.set(rx.len() as i64);
+                    for (mode, rooms) in &ModeRooms {
+                        let mm = Matchmakers.entry(mode.clone())
+                            .or_insert_with(|| create_matchmaker(config.mode(mode)).expect("matchmaker checked at config load"));
+                        let games = mm.find_matches(rooms);
+                        for fg in games {
+                            sender.send(RoomEventData::UpdateGame(PreGameData{rid: fg.group.clone(), mode: mode.clone(), roles: fg.roles.clone()}));
+                            metrics::MATCHES_TOTAL.inc();
+                            for rids in &fg.group {
+                                for rid in rids {
+                                    QueueRoom.remove(rid);
+                                    if let Some(t) = QueueStart.remove(rid) {
+                                        metrics::QUEUE_WAIT.observe(new_now.duration_since(t).as_secs_f64());
+                                    }
                                 }
                             }
                         }
-
-                        //println!("Time 3: {:?}",Instant::now().duration_since(new_now2));
                     }
-                    
-
-
+                    //println!("Time 3: {:?}",Instant::now().duration_since(new_now));
                 }
 
                 recv(rx) -> d => {
@@ -709,32 +1608,24 @@ pub fn HandleQueueRequest(msgtx: Sender<MqttMsg>, sender: Sender<RoomEventData>)
                             match d {
                                 QueueData::UpdateRoom(x) => {
                                     //println!("rid: {}", x.rid);
+                                    QueueStart.entry(x.rid).or_insert(Instant::now());
                                     QueueRoom.insert(x.rid.clone(), Rc::new(RefCell::new(x.clone())));
                                 }
                                 QueueData::RemoveRoom(x) => {
                                     //println!("Remove Room!!!  x.rid: {}", &x.rid);
-                                    let r = QueueRoom.get(&x.rid);
-                                    if let Some(r) = r {
-                                        //println!("Start Remove!! gid: {}", &r.borrow().gid);
-                                        let mut rg = ReadyGroups.get(&r.borrow().gid);
-                                        if let Some(rg) = rg {
-                                            //println!("ReadyGroup Remove!!");
-                                            for rid in &rg.borrow().rid {
-                                                if rid == &x.rid {
-                                                    continue;
-                                                }
-                                                let mut room = QueueRoom.get(rid);
-                                                if let Some(room) = room {
-                                                    
-                                                    room.borrow_mut().gid = 0;
-                                                    room.borrow_mut().ready = 0;
-                                                    //println!("gid: {}, rid: {}, ready: {}", room.borrow().gid, room.borrow().rid, room.borrow().ready);
-                                                }
-                                            }
-                                        }
-                                        ReadyGroups.remove(&r.borrow().gid);
+                                    let mut mode = String::new();
+                                    if let Some(r) = QueueRoom.get(&x.rid) {
+                                        mode = r.borrow().mode.clone();
+                                    }
+                                    if let Some(mm) = Matchmakers.get_mut(&mode) {
+                                        mm.remove_room(x.rid, &QueueRoom);
                                     }
                                     QueueRoom.remove(&x.rid);
+                                    QueueStart.remove(&x.rid);
+                                }
+                                QueueData::Snapshot(reply) => {
+                                    let rooms: Vec<QueueRoomData> = QueueRoom.values().map(|r| r.borrow().clone()).collect();
+                                    reply.try_send((200, json!({"msg": "ok", "total": rooms.len(), "rooms": rooms}).to_string()));
                                 }
                             }
                         }
@@ -752,7 +1643,7 @@ pub fn HandleQueueRequest(msgtx: Sender<MqttMsg>, sender: Sender<RoomEventData>)
 }
 
 
-pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool, QueueSender1: Option<Sender<QueueData>>, isBackup: bool) 
+pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool, QueueSender1: Option<Sender<QueueData>>, isBackup: bool, config: Config) 
     -> Result<(Sender<RoomEventData>, Sender<QueueData>), Error> {
     let (tx, rx):(Sender<RoomEventData>, Receiver<RoomEventData>) = bounded(10000);
     let mut tx1: Sender<QueueData>;
@@ -762,7 +1653,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
             println!("in");
         },
         None => {
-            tx1 = HandleQueueRequest(msgtx.clone(), tx.clone())?;
+            tx1 = HandleQueueRequest(msgtx.clone(), tx.clone(), config.clone())?;
             println!("2 in");
         },
     }
@@ -772,7 +1663,9 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
     let update5000ms = tick(Duration::from_millis(5000));
     let update200ms = tick(Duration::from_millis(200));
     let update1000ms = tick(Duration::from_millis(1000));
+    let update3600s = tick(Duration::from_secs(3600));
     let QueueSender = tx1.clone();
+    let ReadSender = HandleReadRequest(pool.clone(), config.clone(), msgtx.clone())?;
 
     let tx2 = tx.clone();
     thread::spawn(move || -> Result<(), Error> {
@@ -784,17 +1677,46 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
         let mut ReadyGroups: BTreeMap<u32, Rc<RefCell<FightGroup>>> = BTreeMap::new();
         let mut PreStartGroups: BTreeMap<u32, Rc<RefCell<FightGame>>> = BTreeMap::new();
         let mut GameingGroups: BTreeMap<u32, Rc<RefCell<FightGame>>> = BTreeMap::new();
+        let mut SettledGames: BTreeMap<u32, SettledGame> = BTreeMap::new();
+        // games already rolled back, checked before any sql is queued so a second rollback can't slip in
+        let mut VoidGames = VoidGames::default();
         let mut TotalUsers: BTreeMap<String, Rc<RefCell<User>>> = BTreeMap::new();
         let mut LossSend: Vec<MqttMsg> = vec![];
         let mut room_id: u32 = 0;
         let mut group_id: u32 = 0;
         let mut game_id: u32 = 0;
         let mut game_port: u16 = 7777;
+        let mut season: u32 = 0;
+        let mut season_active = true;
+
+        let qres: mysql::QueryResult = conn.query("select id, end_time is null as active from season order by id desc limit 1;")?;
+        for row in qres {
+            let a = row?.clone();
+            season = mysql::from_value(a.get("id").unwrap());
+            season_active = mysql::from_value::<u8>(a.get("active").unwrap()) != 0;
+        }
+        let qres: mysql::QueryResult = conn.query("select game_id from void_game;")?;
+        for row in qres {
+            let a = row?.clone();
+            VoidGames.insert(mysql::from_value(a.get("game_id").unwrap()));
+        }
+        if season == 0 {
+            season = 1;
+            if !isBackup {
+                conn.query("insert into season (id, start_time) values (1, now());")?;
+            }
+        }
 
-        let sql = format!(r#"select userid, a.score as ng, b.score as rk, name from user as c 
+        let sql = format!(r#"select userid, a.score as ng, b.score as rk, name, d.games as games, d.streak as streak,
+                            d.last_game as last_game, d.last_decay as last_decay, d.decay_pending as decay_pending, f.region as region,
+                            e.level as level, e.in_series as in_series, e.series_wins as series_wins, e.series_losses as series_losses from user as c 
                             join user_ng as a on a.id=c.id 
-                            join user_rank as b on b.id=c.id;"#);
+                            join user_rank as b on b.id=c.id
+                            left join user_rating as d on d.id=c.id
+                            left join user_tier as e on e.id=c.id and e.season={}
+                            left join user_region as f on f.id=c.id;"#, season);
         let qres2: mysql::QueryResult = conn.query(sql.clone())?;
+        let now = unix_time();
         let mut userid: String = "".to_owned();
         let mut ng: i16 = 0;
         let mut rk: i16 = 0;
@@ -808,6 +1730,20 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                 online: false,
                 ng: mysql::from_value(a.get("ng").unwrap()),
                 rk: mysql::from_value(a.get("rk").unwrap()),
+                // players from before placement games have no user_rating row and are already ranked
+                games: mysql::from_value::<Option<u32>>(a.get("games").unwrap()).unwrap_or(config.mode(DEFAULT_MODE).placement_games),
+                streak: mysql::from_value::<Option<i32>>(a.get("streak").unwrap()).unwrap_or(0),
+                // users without a recorded game start their inactivity clock now
+                last_game: mysql::from_value::<Option<u64>>(a.get("last_game").unwrap()).filter(|t| *t > 0).unwrap_or(now),
+                last_decay: mysql::from_value::<Option<u64>>(a.get("last_decay").unwrap()).unwrap_or(0),
+                decay_pending: mysql::from_value::<Option<i16>>(a.get("decay_pending").unwrap()).unwrap_or(0),
+                region: mysql::from_value::<Option<String>>(a.get("region").unwrap()).unwrap_or_default(),
+                tier: TierState {
+                    level: mysql::from_value::<Option<u16>>(a.get("level").unwrap()).unwrap_or(0),
+                    in_series: mysql::from_value::<Option<u8>>(a.get("in_series").unwrap()).unwrap_or(0) != 0,
+                    series_wins: mysql::from_value::<Option<u16>>(a.get("series_wins").unwrap()).unwrap_or(0),
+                    series_losses: mysql::from_value::<Option<u16>>(a.get("series_losses").unwrap()).unwrap_or(0),
+                },
                 ..Default::default()
             };
             userid = mysql::from_value(a.get("userid").unwrap());
@@ -817,6 +1753,30 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
             //name = mysql::from_value(a.get("name").unwrap());
             TotalUsers.insert(userid, Rc::new(RefCell::new(user.clone())));
         }
+        let qres: mysql::QueryResult = conn.query("select u.userid as userid, d.backend as backend, d.rd as rd, d.vol as vol from user_deviation as d join user as u on u.id=d.id;")?;
+        for row in qres {
+            let a = row?.clone();
+            let userid: String = mysql::from_value(a.get("userid").unwrap());
+            if let Some(u) = TotalUsers.get(&userid) {
+                u.borrow_mut().deviations.insert(mysql::from_value(a.get("backend").unwrap()),
+                    Deviation {rd: mysql::from_value(a.get("rd").unwrap()), vol: mysql::from_value(a.get("vol").unwrap())});
+            }
+        }
+
+        // leaderboards are served from memory, past seasons come from the archive
+        let mut Boards: Leaderboards = Default::default();
+        for (_, u) in &TotalUsers {
+            update_boards(&mut Boards, &u.borrow(), season, config.mode(DEFAULT_MODE).placement_games);
+        }
+        let qres: mysql::QueryResult = conn.query("select u.userid as userid, h.season as season, h.ng as ng, h.rk as rk, f.region as region from season_history as h join user as u on u.id=h.id left join user_region as f on f.id=h.id;")?;
+        for row in qres {
+            let a = row?.clone();
+            let userid: String = mysql::from_value(a.get("userid").unwrap());
+            let s: u32 = mysql::from_value(a.get("season").unwrap());
+            let region = mysql::from_value::<Option<String>>(a.get("region").unwrap()).unwrap_or_default();
+            Boards.update("ng", &region, s, &userid, mysql::from_value(a.get("ng").unwrap()));
+            Boards.update("rk", &region, s, &userid, mysql::from_value(a.get("rk").unwrap()));
+        }
 
         /*
         let get_game_id = format!("select MAX(game_id) from game_info;");
@@ -858,6 +1818,8 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                 group.borrow_mut().ready();
                                 group.borrow_mut().update_names();
                                 group.borrow_mut().game_port = game_port;
+                                let secret = game_secret(&config.server_key, group.borrow().game_id);
+                                group.borrow_mut().secret = secret;
                                 
                                 GameingGroups.remove(&group.borrow().game_id);
                                 //PreStartGroups.remove(&group.borrow().game_id);
@@ -868,6 +1830,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                 let cmd = Command::new("/root/LinuxNoEditor/CF1/Binaries/Linux/CF1Server")
                                         .arg(format!("-Port={}", game_port))
                                         .arg(format!("-gameid {}", group.borrow().game_id))
+                                        .arg(format!("-secret {}", group.borrow().secret))
                                         .arg("-NOSTEAM")
                                         .spawn();
                                         match cmd {
@@ -909,15 +1872,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                 for t in &group.borrow().teams {
                                     for r in &t.borrow().rooms {
                                         if !rm_rid.contains(&r.borrow().rid) {
-                                            let mut data = QueueRoomData {
-                                                rid: r.borrow().rid.clone(),
-                                                gid: 0,
-                                                user_len: r.borrow().users.len().clone() as i16,
-                                                avg_ng: r.borrow().avg_ng.clone(),
-                                                avg_rk: r.borrow().avg_rk.clone(),
-                                                ready: 0,
-                                                queue_cnt: 1,
-                                            };
+                                            let mut data = get_queue_data(r, config.mode(&r.borrow().mode));
                                             QueueSender.send(QueueData::UpdateRoom(data));
                                         }
                                     }
@@ -934,15 +1889,58 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                     }
                 }
                 recv(update1000ms) -> _ => {
+                    metrics::ACTIVE_GAMES.set(GameingGroups.len() as i64);
+                    metrics::ROOM_CHANNEL.set(rx.len() as i64);
+                    metrics::SQL_CHANNEL.set(sender.len() as i64);
+                    metrics::MQTT_CHANNEL.set(msgtx.len() as i64);
                     if !isBackup || (isBackup && isServerLive == false) {
                         //msgtx.try_send(MqttMsg{topic:format!("server/0/res/heartbeat"), 
                         //                    msg: format!(r#"{{"msg":"live"}}"#)})?;
                     }
                 }
 
+                recv(update3600s) -> _ => {
+                    let now = unix_time();
+                    let decay = &config.decay;
+                    let live = !isBackup || (isBackup && isServerLive == false);
+                    for (id, user) in &TotalUsers {
+                        {
+                            let mut u = user.borrow_mut();
+                            if !decay.due(u.rk, u.last_game, u.last_decay, now) {
+                                continue;
+                            }
+                            let rk_before = u.rk;
+                            let backend = backend_name(config.mode(DEFAULT_MODE));
+                            let rd_before = u.deviation(backend).rd;
+                            u.rk = std::cmp::max(u.rk - decay.rk_amount, decay.threshold);
+                            // rd_amount is on the glicko2 scale, other backends grow by the same share of their initial rd
+                            for (b, d) in u.deviations.iter_mut() {
+                                let init = initial_deviation(b).rd;
+                                d.rd = (d.rd + decay.rd_amount * init / DEFAULT_RD).min(init);
+                            }
+                            let rd_after = u.deviation(backend).rd;
+                            u.last_decay = now;
+                            u.decay_pending += rk_before - u.rk;
+                            update_boards(&mut Boards, &u, season, config.mode(DEFAULT_MODE).placement_games);
+                            if live {
+                                sender.send(SqlData::Decay(SqlDecayData {id: id.clone(), time: now, rk_before: rk_before, rk_after: u.rk,
+                                    rd_before: rd_before, rd_after: rd_after, pending: u.decay_pending, deviations: u.deviations.clone()}));
+                                sender.send(SqlData::Ledger(SqlLedgerData {id: id.clone(), time: now, ng_before: u.ng, ng_after: u.ng,
+                                    rk_before: rk_before, rk_after: u.rk, rd_before: rd_before, rd_after: rd_after, vol_before: u.deviation(backend).vol, tier_before: u.tier.level,
+                                    reason: "decay".to_string(), ..Default::default()}));
+                            }
+                        }
+                        update_tier(user, &config.tiers, config.mode(DEFAULT_MODE), season, None, live, &msgtx, &sender)?;
+                    }
+                }
+
                 recv(update5000ms) -> _ => {
                     //println!("rx len: {}, tx len: {}", rx.len(), tx2.len());
                     LossSend.clear();
+                    let expired: Vec<u32> = SettledGames.iter().filter(|(_, s)| s.time.elapsed() >= SETTLED_KEEP).map(|(g, _)| *g).collect();
+                    for g in expired {
+                        SettledGames.remove(&g);
+                    }
                     for (id, group) in &mut PreStartGroups {
                         let res1 = group.borrow().check_prestart_get();
                         //println!("check result: {}", res1);
@@ -986,6 +1984,275 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     }
                                     //info!("Status TotalUsers {:#?}", TotalUsers);
                                 },
+                                RoomEventData::SeasonEnd(x) => {
+                                    if !sig_ok(&config.http.admin_token, &x.payload, &x.sig) {
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/season_end", x.id), 
+                                            msg: r#"{"msg":"fail", "reason":"unauthorized"}"#.to_string()};
+                                    } else if !season_active {
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/season_end", x.id), 
+                                            msg: format!(r#"{{"msg":"fail", "reason":"season {} already ended"}}"#, season)};
+                                    } else {
+                                        let placement_games = config.mode(DEFAULT_MODE).placement_games;
+                                        let mut users = vec![];
+                                        for (id, u) in &TotalUsers {
+                                            let u = u.borrow();
+                                            if u.games >= placement_games {
+                                                users.push(SqlSeasonUserData {id: id.clone(), ng: u.ng, rk: u.rk, level: u.tier.level, tier: config.tiers.name(u.tier.level)});
+                                            }
+                                        }
+                                        if !isBackup || (isBackup && isServerLive == false) {
+                                            sender.send(SqlData::SeasonEnd(SqlSeasonEndData {season: season, users: users}));
+                                        }
+                                        season_active = false;
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/season_end", x.id), 
+                                            msg: format!(r#"{{"msg":"ok", "season":{}}}"#, season)};
+                                    }
+                                },
+                                RoomEventData::SeasonStart(x) => {
+                                    if !sig_ok(&config.http.admin_token, &x.payload, &x.sig) {
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/season_start", x.id), 
+                                            msg: r#"{"msg":"fail", "reason":"unauthorized"}"#.to_string()};
+                                    } else if season_active {
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/season_start", x.id), 
+                                            msg: format!(r#"{{"msg":"fail", "reason":"season {} has not ended"}}"#, season)};
+                                    } else {
+                                        season += 1;
+                                        season_active = true;
+                                        let placement_games = config.mode(DEFAULT_MODE).placement_games;
+                                        let mut tiers = vec![];
+                                        for (id, u) in &TotalUsers {
+                                            let mut u = u.borrow_mut();
+                                            u.ng = config.season.soft_reset(u.ng);
+                                            u.rk = config.season.soft_reset(u.rk);
+                                            u.tier = Default::default();
+                                            if u.games >= placement_games {
+                                                u.tier = config.tiers.place(u.rk);
+                                                tiers.push((id.clone(), u.tier.clone()));
+                                            }
+                                            update_boards(&mut Boards, &u, season, placement_games);
+                                        }
+                                        if !isBackup || (isBackup && isServerLive == false) {
+                                            sender.send(SqlData::SeasonStart(SqlSeasonStartData {season: season, reset: config.season.clone(), tiers: tiers}));
+                                        }
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/season_start", x.id), 
+                                            msg: format!(r#"{{"msg":"ok", "season":{}}}"#, season)};
+                                    }
+                                },
+                                RoomEventData::SeasonHistory(x) => {
+                                    if !isBackup || (isBackup && isServerLive == false) {
+                                        ReadSender.try_send(ReadData::SeasonHistory(x, season));
+                                    }
+                                },
+                                RoomEventData::RatingHistory(x) => {
+                                    if !isBackup || (isBackup && isServerLive == false) {
+                                        ReadSender.try_send(ReadData::RatingHistory(x));
+                                    }
+                                },
+                                RoomEventData::Rollback(x) => {
+                                    if !sig_ok(&config.http.admin_token, &x.payload, &x.sig) {
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/rollback", x.id), 
+                                            msg: r#"{"msg":"fail", "reason":"unauthorized"}"#.to_string()};
+                                    } else if let Err(reason) = VoidGames.claim(x.game) {
+                                        mqttmsg = MqttMsg{topic:format!("admin/{}/res/rollback", x.id), 
+                                            msg: json!({"msg": "fail", "reason": reason}).to_string()};
+                                    } else {
+                                        let sql = format!("select u.userid as userid, l.mode as mode, l.ng_delta as ng_delta, l.rk_before as rk_before, l.rk_after as rk_after, l.rd_before as rd_before, l.vol_before as vol_before, l.tier_before as tier_before from rating_ledger as l join user as u on u.id=l.id where l.game_id={} and l.reason!='rollback' order by l.seq;", x.game);
+                                        let qres: mysql::QueryResult = conn.query(sql.clone())?;
+                                        let mut ledger_rows: Vec<LedgerRow> = vec![];
+                                        for row in qres {
+                                            let a = row?.clone();
+                                            let rk_before: i16 = mysql::from_value(a.get("rk_before").unwrap());
+                                            let rk_after: i16 = mysql::from_value(a.get("rk_after").unwrap());
+                                            ledger_rows.push(LedgerRow {id: mysql::from_value(a.get("userid").unwrap()), mode: mysql::from_value(a.get("mode").unwrap()),
+                                                ng_delta: mysql::from_value(a.get("ng_delta").unwrap()), rk_delta: rk_after - rk_before, rd_before: mysql::from_value(a.get("rd_before").unwrap()),
+                                                vol_before: mysql::from_value(a.get("vol_before").unwrap()), tier_before: mysql::from_value(a.get("tier_before").unwrap())});
+                                        }
+                                        let live = !isBackup || (isBackup && isServerLive == false);
+                                        let rows = VoidGames.rows(x.game, ledger_rows);
+                                        for LedgerRow {id, mode, ng_delta, rk_delta, rd_before, vol_before, tier_before} in rows.iter().flatten() {
+                                            if let Some(u) = TotalUsers.get(id) {
+                                                let backend = backend_name(config.mode(mode));
+                                                let mut ledger = SqlLedgerData {id: id.clone(), game: x.game, mode: mode.clone(), time: unix_time(),
+                                                    ng_before: u.borrow().ng, rk_before: u.borrow().rk, rd_before: u.borrow().deviation(backend).rd,
+                                                    vol_before: u.borrow().deviation(backend).vol, tier_before: u.borrow().tier.level, reason: "rollback".to_string(), ..Default::default()};
+                                                {
+                                                    let mut u = u.borrow_mut();
+                                                    u.ng -= ng_delta;
+                                                    u.rk -= rk_delta;
+                                                    let vol = vol_before.unwrap_or(u.deviation(backend).vol);
+                                                    u.deviations.insert(backend.to_string(), Deviation {rd: *rd_before, vol: vol});
+                                                    if let Some(level) = tier_before {
+                                                        u.tier = TierState {level: *level, ..Default::default()};
+                                                    }
+                                                    if u.games > 0 {
+                                                        u.games -= 1;
+                                                    }
+                                                    u.streak = 0;
+                                                }
+                                                ledger.ng_after = u.borrow().ng;
+                                                ledger.rk_after = u.borrow().rk;
+                                                ledger.rd_after = *rd_before;
+                                                update_tier(u, &config.tiers, config.mode(mode), season, None, live, &msgtx, &sender)?;
+                                                update_boards(&mut Boards, &u.borrow(), season, config.mode(DEFAULT_MODE).placement_games);
+                                                if live {
+                                                    if tier_before.is_some() {
+                                                        sender.send(SqlData::UpdateTier(SqlTierData {id: id.clone(), season: season, tier: u.borrow().tier.clone()}));
+                                                    }
+                                                    let dev = u.borrow().deviation(backend);
+                                                    sender.send(SqlData::UpdateScore(SqlScoreData {id: id.clone(), score: u.borrow().ng, rk: u.borrow().rk, backend: backend.to_string(), rd: dev.rd, vol: dev.vol,
+                                                        games: u.borrow().games, streak: u.borrow().streak, last_game: u.borrow().last_game}));
+                                                    sender.send(SqlData::Ledger(ledger));
+                                                    if u.borrow().online {
+                                                        msgtx.try_send(MqttMsg{topic:format!("member/{}/res/login", id), 
+                                                            msg: login_msg(&u.borrow(), config.mode(DEFAULT_MODE))})?;
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        match rows {
+                                            Ok(rows) => {
+                                                if live {
+                                                    sender.send(SqlData::VoidGame(SqlVoidData {game: x.game, time: unix_time(), admin: x.id.clone()}));
+                                                }
+                                                mqttmsg = MqttMsg{topic:format!("admin/{}/res/rollback", x.id), 
+                                                    msg: format!(r#"{{"msg":"ok", "game":{}, "users":{}}}"#, x.game, rows.len())};
+                                            },
+                                            Err(reason) => {
+                                                mqttmsg = MqttMsg{topic:format!("admin/{}/res/rollback", x.id), 
+                                                    msg: json!({"msg": "fail", "reason": reason}).to_string()};
+                                            },
+                                        }
+                                    }
+                                },
+                                RoomEventData::LeaderboardQuery(x) => {
+                                    mqttmsg = MqttMsg{topic:format!("leaderboard/{}/res/query", x.id), 
+                                        msg: leaderboard_msg(&Boards, &x, season, false).to_string()};
+                                },
+                                RoomEventData::Http(x) => {
+                                    let param = |k: &str| x.params.get(k).cloned().unwrap_or_default();
+                                    let res: (u16, Value) = match x.path.as_str() {
+                                        "/users" => {
+                                            let users: Vec<Value> = TotalUsers.values().filter(|u| u.borrow().online).map(|u| {
+                                                let u = u.borrow();
+                                                json!({"id": u.id, "name": u.name, "hero": u.hero, "ng": u.ng, "rk": u.rk, "region": u.region,
+                                                    "tier": config.tiers.name(u.tier.level), "rid": u.rid, "gid": u.gid, "game": u.game_id})
+                                            }).collect();
+                                            (200, json!({"msg": "ok", "total": users.len(), "users": users}))
+                                        },
+                                        "/rooms" => {
+                                            let rooms: Vec<Value> = TotalRoom.values().map(|r| {
+                                                let r = r.borrow();
+                                                json!({"rid": r.rid, "master": r.master, "mode": r.mode, "ready": r.ready, "queue_cnt": r.queue_cnt,
+                                                    "avg_ng": r.avg_ng, "avg_rk": r.avg_rk, "users": r.users.iter().map(|u| u.borrow().id.clone()).collect::<Vec<String>>()})
+                                            }).collect();
+                                            (200, json!({"msg": "ok", "total": rooms.len(), "rooms": rooms}))
+                                        },
+                                        "/queue" => {
+                                            // answered by the queue thread
+                                            QueueSender.send(QueueData::Snapshot(x.reply.clone()));
+                                            return Ok(());
+                                        },
+                                        "/prestart" => {
+                                            let ready: Vec<Value> = ReadyGroups.iter().map(|(gid, g)| {
+                                                let g = g.borrow();
+                                                json!({"gid": gid, "rids": g.rids, "user_count": g.user_count, "avg_ng": g.avg_ng,
+                                                    "checks": g.checks.iter().map(|c| json!({"id": c.id, "check": c.check})).collect::<Vec<Value>>()})
+                                            }).collect();
+                                            let prestart: Vec<Value> = PreStartGroups.iter().map(|(id, g)| {
+                                                let g = g.borrow();
+                                                json!({"game": id, "mode": g.mode, "rooms": g.room_names, "users": g.user_names})
+                                            }).collect();
+                                            (200, json!({"msg": "ok", "ready": ready, "prestart": prestart}))
+                                        },
+                                        "/games" => {
+                                            let games: Vec<Value> = GameingGroups.iter().map(|(id, g)| {
+                                                let g = g.borrow();
+                                                json!({"game": id, "mode": g.mode, "port": g.game_port, "status": g.game_status,
+                                                    "rooms": g.room_names, "users": g.user_names})
+                                            }).collect();
+                                            (200, json!({"msg": "ok", "total": games.len(), "games": games}))
+                                        },
+                                        "/leaderboard" | "/admin/leaderboard" => {
+                                            let q = LeaderboardQueryData {
+                                                id: param("id"),
+                                                mode: param("mode"),
+                                                region: param("region"),
+                                                season: param("season").parse().unwrap_or(0),
+                                                kind: param("kind"),
+                                                offset: param("offset").parse().unwrap_or(0),
+                                                count: param("count").parse().unwrap_or(0),
+                                            };
+                                            (200, leaderboard_msg(&Boards, &q, season, x.path == "/admin/leaderboard"))
+                                        },
+                                        "/admin/kick" => {
+                                            let id = param("id");
+                                            if TotalUsers.contains_key(&id) {
+                                                tx2.try_send(RoomEventData::Logout(UserLogoutData {id: id.clone()}));
+                                                if !isBackup || (isBackup && isServerLive == false) {
+                                                    msgtx.try_send(MqttMsg{topic:format!("member/{}/res/kick", id), 
+                                                        msg: format!(r#"{{"msg":"kick"}}"#)})?;
+                                                }
+                                                (200, json!({"msg": "ok", "id": id}))
+                                            } else {
+                                                (404, json!({"msg": "id not found", "id": id}))
+                                            }
+                                        },
+                                        "/admin/cancel_game" => {
+                                            let game: u32 = param("game").parse().unwrap_or(0);
+                                            match GameingGroups.get(&game) {
+                                                Some(g) => {
+                                                    // closed like a game server would, without a result
+                                                    let secret = g.borrow().secret.clone();
+                                                    if !isBackup || (isBackup && isServerLive == false) {
+                                                        msgtx.try_send(MqttMsg{topic:format!("game/{}/res/cancel", game), 
+                                                            msg: format!(r#"{{"msg":"cancel"}}"#)})?;
+                                                        for id in &g.borrow().user_names {
+                                                            msgtx.try_send(MqttMsg{topic:format!("member/{}/res/game_cancel", id), 
+                                                                msg: format!(r#"{{"msg":"cancel", "game":{}}}"#, game)})?;
+                                                        }
+                                                    }
+                                                    let payload = json!({"game": game}).to_string();
+                                                    tx2.try_send(RoomEventData::GameClose(GameCloseData {game: game, sig: sign(&secret, &payload), payload: payload}));
+                                                    (200, json!({"msg": "ok", "game": game}))
+                                                },
+                                                None => (404, json!({"msg": "game not found", "game": game})),
+                                            }
+                                        },
+                                        "/admin/reset" => {
+                                            tx2.try_send(RoomEventData::Reset());
+                                            (200, json!({"msg": "ok"}))
+                                        },
+                                        _ => (404, json!({"msg": "not found"})),
+                                    };
+                                    x.reply.try_send((res.0, res.1.to_string()));
+                                },
+                                RoomEventData::Profile(x) => {
+                                    match TotalUsers.get(&x.id) {
+                                        Some(u) => {
+                                            if !isBackup || (isBackup && isServerLive == false) {
+                                                ReadSender.try_send(ReadData::Profile(u.borrow().clone()));
+                                            }
+                                        },
+                                        None => {
+                                            mqttmsg = MqttMsg{topic:format!("member/{}/res/profile", x.id), 
+                                                msg: format!(r#"{{"msg":"id not found"}}"#)};
+                                        }
+                                    }
+                                },
+                                RoomEventData::MatchHistory(x) => {
+                                    if !isBackup || (isBackup && isServerLive == false) {
+                                        ReadSender.try_send(ReadData::MatchHistory(x));
+                                    }
+                                },
+                                RoomEventData::BadReport(x) => {
+                                    mqttmsg = MqttMsg{topic: x.topic.clone(), 
+                                        msg: json!({"msg": "fail", "reason": x.reason}).to_string()};
+                                },
+                                RoomEventData::HeroStatsQuery(x) => {
+                                    if !isBackup || (isBackup && isServerLive == false) {
+                                        ReadSender.try_send(ReadData::HeroStats(x));
+                                    }
+                                },
                                 RoomEventData::Reconnect(x) => {
                                     let u = get_user(&x.id, &TotalUsers);
                                     if let Some(u) = u {
@@ -999,6 +2266,13 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     }
                                 },
                                 RoomEventData::GameClose(x) => {
+                                    if !check_sig(GameingGroups.get(&x.game), &x.payload, &x.sig) {
+                                        if !isBackup || (isBackup && isServerLive == false) {
+                                            msgtx.try_send(MqttMsg{topic:format!("game/{}/res/game_close", x.game), 
+                                                msg: format!(r#"{{"msg":"fail", "reason":"bad signature"}}"#)})?;
+                                        }
+                                        return Ok(());
+                                    }
                                     //let p = PreStartGroups.remove(&x.game);
                                     let g = GameingGroups.remove(&x.game);
                                     if let Some(g) = g {
@@ -1027,6 +2301,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                                     u.borrow_mut().rid = 0;
                                                     u.borrow_mut().gid = 0;
                                                     u.borrow_mut().game_id = 0;
+                                                    u.borrow_mut().role = String::new();
                                                 },
                                                 None => {
                                                     //info!("remove fail ");
@@ -1041,9 +2316,41 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     }
                                 },
                                 RoomEventData::GameOver(x) => {
-                                    let win = get_users(&x.win, &TotalUsers)?;
-                                    let lose = get_users(&x.lose, &TotalUsers)?;
-                                    settlement_ng_score(&win, &lose, &msgtx, &sender, &mut conn);
+                                    if let Some(reason) = check_game_over(&x, GameingGroups.get(&x.game), &SettledGames) {
+                                        if !isBackup || (isBackup && isServerLive == false) {
+                                            msgtx.try_send(MqttMsg{topic:format!("game/{}/res/game_over", x.reporter), 
+                                                msg: json!({"msg": "fail", "reason": reason}).to_string()})?;
+                                        }
+                                        return Ok(());
+                                    }
+                                    let mode = {
+                                        let g = GameingGroups.get(&x.game).unwrap().borrow();
+                                        let kept = FightGame {game_id: g.game_id, user_names: g.user_names.clone(), secret: g.secret.clone(), info_ids: g.info_ids.clone(), ..Default::default()};
+                                        SettledGames.insert(x.game, SettledGame {time: Instant::now(), game: Rc::new(RefCell::new(kept))});
+                                        g.mode.clone()
+                                    };
+                                    let live = !isBackup || (isBackup && isServerLive == false);
+                                    let mut teams = vec![];
+                                    if x.placements.len() > 0 {
+                                        for t in &x.placements {
+                                            teams.push(get_users(t, &TotalUsers)?);
+                                        }
+                                        settlement_placement(&teams, x.game, &mode, config.mode(&mode), live, &msgtx, &sender, &mut conn)?;
+                                    }
+                                    else {
+                                        teams.push(get_users(&x.win, &TotalUsers)?);
+                                        teams.push(get_users(&x.lose, &TotalUsers)?);
+                                        settlement_ng_score(&teams[0], &teams[1], x.draw, x.game, &mode, config.mode(&mode), live, &msgtx, &sender, &mut conn)?;
+                                    }
+                                    for t in &teams {
+                                        for u in t {
+                                            let result = u.borrow().streak.signum();
+                                            update_tier(u, &config.tiers, config.mode(&mode), season, Some(result), live, &msgtx, &sender)?;
+                                            update_boards(&mut Boards, &u.borrow(), season, config.mode(DEFAULT_MODE).placement_games);
+                                        }
+                                    }
+                                    mqttmsg = MqttMsg{topic:format!("game/{}/res/game_over", x.reporter), 
+                                        msg: format!(r#"{{"msg":"ok", "game":{}}}"#, x.game)};
                                     // remove game
                                     let g = GameingGroups.remove(&x.game);
                                     match g {
@@ -1078,6 +2385,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                                     u.borrow_mut().rid = 0;
                                                     u.borrow_mut().gid = 0;
                                                     u.borrow_mut().game_id = 0;
+                                                    u.borrow_mut().role = String::new();
                                                 },
                                                 None => {
                                                     error!("remove fail ");
@@ -1112,6 +2420,27 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     }
                                 },
                                 RoomEventData::GameInfo(x) => {
+                                    // game servers may send stats after game_over
+                                    let game = match GameingGroups.get(&x.game) {
+                                        Some(g) => Some(g.clone()),
+                                        None => SettledGames.get(&x.game).map(|s| s.game.clone()),
+                                    };
+                                    if let Some(reason) = check_game_info(&x, game.as_ref()) {
+                                        if !isBackup || (isBackup && isServerLive == false) {
+                                            msgtx.try_send(MqttMsg{topic:format!("game/{}/res/game_info", x.reporter), 
+                                                msg: json!({"msg": "fail", "game": x.game, "reason": reason}).to_string()})?;
+                                        }
+                                        return Ok(());
+                                    }
+                                    if let Some(g) = &game {
+                                        g.borrow_mut().info_ids.extend(x.users.iter().map(|u| u.id.clone()));
+                                        let g = g.borrow();
+                                        if g.user_names.iter().all(|id| g.info_ids.contains(id)) {
+                                            SettledGames.remove(&x.game);
+                                        }
+                                    }
+                                    mqttmsg = MqttMsg{topic:format!("game/{}/res/game_info", x.reporter), 
+                                        msg: format!(r#"{{"msg":"ok", "game":{}}}"#, x.game)};
                                     //println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
                                     for u in &x.users {
                                         let mut update_info: SqlGameInfoData = Default::default();
@@ -1119,12 +2448,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                         update_info.id = u.id.clone();
                                         update_info.hero = u.hero.clone();
                                         update_info.level = u.level.clone();
-                                        for (i, e) in u.equ.iter().enumerate() {
-                                            update_info.equ += e;
-                                            if i < u.equ.len()-1 {
-                                                update_info.equ += ", ";
-                                            }
-                                        }
+                                        update_info.equ = u.equ.clone();
                                         update_info.damage = u.damage.clone();
                                         update_info.take_damage = u.take_damage.clone();
                                         update_info.heal = u.heal.clone();
@@ -1137,6 +2461,13 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     }
                                 },
                                 RoomEventData::StartGame(x) => {
+                                    if !check_sig(GameingGroups.get(&x.game), &x.payload, &x.sig) {
+                                        if !isBackup || (isBackup && isServerLive == false) {
+                                            msgtx.try_send(MqttMsg{topic:format!("game/{}/res/start_game", x.game), 
+                                                msg: format!(r#"{{"msg":"fail", "reason":"bad signature"}}"#)})?;
+                                        }
+                                        return Ok(());
+                                    }
                                     let g = GameingGroups.get(&x.game);
                                     if let Some(g) = g {
                                         SendGameList(&g, &msgtx, &mut conn);
@@ -1190,6 +2521,14 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                         //    msg: format!(r#"{{"id":"{}", "hero":"{}"}}"#, u.borrow().id, u.borrow().hero)})?;
                                     }
                                 },
+                                RoomEventData::ChooseRole(x) => {
+                                    let u = TotalUsers.get(&x.id);
+                                    if let Some(u) = u {
+                                        u.borrow_mut().roles = x.roles.clone();
+                                        mqttmsg = MqttMsg{topic:format!("member/{}/res/choose_role", u.borrow().id), 
+                                            msg: json!({"id": u.borrow().id, "roles": u.borrow().roles}).to_string()};
+                                    }
+                                },
                                 RoomEventData::Invite(x) => {
                                     if TotalUsers.contains_key(&x.from) {
                                         mqttmsg = MqttMsg{topic:format!("room/{}/res/invite", x.invite.clone()), 
@@ -1202,12 +2541,21 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     let u = TotalUsers.get(&x.room);
                                     let j = TotalUsers.get(&x.join);
                                     let mut sendok = false;
+                                    let mut reason = "room not found";
                                     if let Some(u) = u {
                                         if let Some(j) = j {
                                             let r = TotalRoom.get(&u.borrow().rid);
                                             
                                             if let Some(r) = r {
-                                                if r.borrow().ready == 0 && r.borrow().users.len() < TEAM_SIZE as usize {
+                                                let mode = config.mode(&r.borrow().mode);
+                                                let spread_ok = mode.max_party_spread <= 0 || r.borrow().ng_spread_with(j.borrow().ng) <= mode.max_party_spread;
+                                                if r.borrow().ready != 0 {
+                                                    reason = "queueing";
+                                                } else if r.borrow().users.len() >= mode.team_size as usize {
+                                                    reason = "room full";
+                                                } else if !spread_ok {
+                                                    reason = "rating spread";
+                                                } else {
                                                     r.borrow_mut().add_user(Rc::clone(j));
                                                     let m = r.borrow().master.clone();
                                                     r.borrow().publish_update(&msgtx, m)?;
@@ -1225,7 +2573,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                     }
                                     if sendok == false {
                                         mqttmsg = MqttMsg{topic:format!("room/{}/res/join", x.join.clone()), 
-                                            msg: format!(r#"{{"room":"{}","msg":"fail"}}"#, x.room.clone())};
+                                            msg: format!(r#"{{"room":"{}","msg":"fail", "reason":"{}"}}"#, x.room.clone(), reason)};
                                         //msgtx.try_send(MqttMsg{topic:format!("room/{}/res/join", x.join.clone()), 
                                         //    msg: format!(r#"{{"room":"{}","msg":"fail"}}"#, x.room.clone())})?;
                                     }
@@ -1251,6 +2599,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                                 let g = ReadyGroups.get(&gid);
                                                 if let Some(gr) = g {
                                                     if x.accept == true {
+                                                        metrics::PRESTART_ACCEPT.inc();
                                                         gr.borrow_mut().user_ready(&x.id);
                                                         //info!("PreStart user_ready");
                                                         mqttmsg = MqttMsg{topic:format!("room/{}/res/start_get", u.borrow().id), 
@@ -1259,19 +2608,12 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                                         //        msg: format!(r#"{{"msg":"start"}}"#)})?;
                                                     } else {
                                                         println!("accept false!");
+                                                        metrics::PRESTART_DECLINE.inc();
                                                         gr.borrow_mut().user_cancel(&x.id);
                                                         for r in &gr.borrow().rooms {
                                                             println!("r_rid: {}, u_rid: {}", r.borrow().rid, u.borrow().rid);
                                                             if r.borrow().rid != u.borrow().rid {
-                                                                let mut data = QueueRoomData {
-                                                                    rid: r.borrow().rid.clone(),
-                                                                    gid: 0,
-                                                                    user_len: r.borrow().users.len().clone() as i16,
-                                                                    avg_ng: r.borrow().avg_ng.clone(),
-                                                                    avg_rk: r.borrow().avg_rk.clone(),
-                                                                    ready: 0,
-                                                                    queue_cnt: 1,
-                                                                };
+                                                                let mut data = get_queue_data(r, config.mode(&r.borrow().mode));
                                                                 QueueSender.send(QueueData::UpdateRoom(data));
                                                             }
                                                         }
@@ -1316,6 +2658,16 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                         }
                                     }
 
+                                    // games without a role template leave everyone's role empty
+                                    for t in &fg.teams {
+                                        for r in &t.borrow().rooms {
+                                            for u in &r.borrow().users {
+                                                let id = u.borrow().id.clone();
+                                                u.borrow_mut().role = x.roles.get(&id).cloned().unwrap_or_default();
+                                            }
+                                        }
+                                    }
+                                    fg.mode = x.mode.clone();
                                     fg.update_names();
                                     for r in &fg.room_names {
                                         //thread::sleep_ms(100);
@@ -1339,6 +2691,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                 },
                                 RoomEventData::StartQueue(x) => {
                                     let mut success = false;
+                                    let mut reason = "";
                                     let mut hasRoom = false;
                                     let u = TotalUsers.get(&x.id);
                                     let mut rid = 0;
@@ -1352,22 +2705,28 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                         let r = TotalRoom.get(&rid);
                                         if let Some(y) = r {
                                             y.borrow_mut().update_avg();
-                                            let mut data = QueueRoomData {
-                                                rid: y.borrow().rid.clone(),
-                                                gid: 0,
-                                                user_len: y.borrow().users.len().clone() as i16,
-                                                avg_ng: y.borrow().avg_ng.clone(),
-                                                avg_rk: y.borrow().avg_rk.clone(),
-                                                ready: 0,
-                                                queue_cnt: 1,
-                                            };
-                                            //println!("Totalroom rid: {}", rid);
-                                            QueueSender.send(QueueData::UpdateRoom(data));
-                                            //QueueRoom.insert(
-                                            //    y.borrow().rid,
-                                            //    Rc::clone(y)
-                                            //);
-                                            success = true;
+                                            let mode = config.mode(&x.mode);
+                                            let roles: Vec<Vec<String>> = y.borrow().users.iter().map(|u| u.borrow().roles.clone()).collect();
+                                            if !config.has_mode(&x.mode) {
+                                                reason = "unknown mode";
+                                            } else if y.borrow().users.len() > mode.team_size as usize {
+                                                // rooms join before the mode is known, a party too big for the team never matches
+                                                reason = "room too large";
+                                            } else if mode.max_party_spread > 0 && y.borrow().ng_spread() > mode.max_party_spread {
+                                                reason = "rating spread";
+                                            } else if mode.role_template.len() > 0 && assign_roles(&roles, &mode.role_template).is_none() {
+                                                reason = "roles";
+                                            } else {
+                                                y.borrow_mut().mode = x.mode.clone();
+                                                let mut data = get_queue_data(y, mode);
+                                                //println!("Totalroom rid: {}", rid);
+                                                QueueSender.send(QueueData::UpdateRoom(data));
+                                                //QueueRoom.insert(
+                                                //    y.borrow().rid,
+                                                //    Rc::clone(y)
+                                                //);
+                                                success = true;
+                                            }
                                             if success {
                                                 mqttmsg = MqttMsg{topic:format!("room/{}/res/start_queue", y.borrow().master.clone()), 
                                                     msg: format!(r#"{{"msg":"ok"}}"#)};
@@ -1375,7 +2734,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                                 //    msg: format!(r#"{{"msg":"ok"}}"#)})?;
                                             } else {
                                                 mqttmsg = MqttMsg{topic:format!("room/{}/res/start_queue", y.borrow().master.clone()), 
-                                                    msg: format!(r#"{{"msg":"fail"}}"#)}
+                                                    msg: format!(r#"{{"msg":"fail", "reason":"{}"}}"#, reason)}
                                                 //msgtx.try_send(MqttMsg{topic:format!("room/{}/res/start_queue", y.borrow().master.clone()), 
                                                 //    msg: format!(r#"{{"msg":"fail"}}"#)})?;
                                             }
@@ -1421,19 +2780,42 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                         let u2 = TotalUsers.get(&x.u.id);
                                         if let Some(u2) = u2 {
                                             u2.borrow_mut().online = true;
+                                            let old_region = u2.borrow().region.clone();
+                                            if x.u.region != "" && x.u.region != old_region {
+                                                Boards.remove("ng", &old_region, season, &x.u.id);
+                                                Boards.remove("rk", &old_region, season, &x.u.id);
+                                                u2.borrow_mut().region = x.u.region.clone();
+                                                update_boards(&mut Boards, &u2.borrow(), season, config.mode(DEFAULT_MODE).placement_games);
+                                                if !isBackup || (isBackup && isServerLive == false) {
+                                                    sender.send(SqlData::UpdateRegion(SqlRegionData {id: x.dataid.clone(), region: x.u.region.clone()}));
+                                                }
+                                            }
                                             mqttmsg = MqttMsg{topic:format!("member/{}/res/login", u2.borrow().id.clone()), 
-                                                msg: format!(r#"{{"msg":"ok", "ng":{}, "rk":{} }}"#, u2.borrow().ng, u2.borrow().rk)};
+                                                msg: login_msg(&u2.borrow(), config.mode(DEFAULT_MODE))};
+                                            let pending = u2.borrow().decay_pending;
+                                            if pending > 0 {
+                                                u2.borrow_mut().decay_pending = 0;
+                                                if !isBackup || (isBackup && isServerLive == false) {
+                                                    msgtx.try_send(MqttMsg{topic:format!("member/{}/res/decay", u2.borrow().id.clone()), 
+                                                        msg: format!(r#"{{"msg":"decay", "rk_lost":{}, "rk":{} }}"#, pending, u2.borrow().rk)})?;
+                                                    sender.send(SqlData::DecayNotified(u2.borrow().id.clone()));
+                                                }
+                                            }
                                             //msgtx.try_send(MqttMsg{topic:format!("member/{}/res/login", u2.borrow().id.clone()), 
                                             //    msg: format!(r#"{{"msg":"ok", "ng":{}, "rk":{} }}"#, u2.borrow().ng, u2.borrow().rk)})?;
                                         }
                                         
                                     }
                                     else {
+                                        let init = create_rating(config.mode(DEFAULT_MODE))?.initial();
+                                        let mut x = x;
+                                        x.u.ng = init.score.round() as i16;
                                         TotalUsers.insert(x.u.id.clone(), Rc::new(RefCell::new(x.u.clone())));
+                                        update_boards(&mut Boards, &x.u, season, config.mode(DEFAULT_MODE).placement_games);
                                         //thread::sleep(Duration::from_millis(50));
-                                        sender.send(SqlData::Login(SqlLoginData {id: x.dataid.clone(), name: name.clone()}));
+                                        sender.send(SqlData::Login(SqlLoginData {id: x.dataid.clone(), name: name.clone(), rating: init, region: x.u.region.clone()}));
                                         mqttmsg = MqttMsg{topic:format!("member/{}/res/login", x.u.id.clone()), 
-                                            msg: format!(r#"{{"msg":"ok", "ng":{}, "rk":{} }}"#, x.u.ng, x.u.rk)};
+                                            msg: login_msg(&x.u, config.mode(DEFAULT_MODE))};
                                         //msgtx.try_send(MqttMsg{topic:format!("member/{}/res/login", x.u.id.clone()), 
                                         //    msg: format!(r#"{{"msg":"ok", "ng":{}, "rk":{} }}"#, x.u.ng, x.u.rk)})?;
                                     }
@@ -1560,6 +2942,7 @@ pub fn init(msgtx: Sender<MqttMsg>, sender: Sender<SqlData>, pool: mysql::Pool,
                                             avg_rk: 0,
                                             ready: 0,
                                             queue_cnt: 1,
+                                            mode: DEFAULT_MODE.to_string(),
                                         };
                                         let mut u = TotalUsers.get(&x.id);
                                         if let Some(u) = u {
@@ -1676,8 +3059,11 @@ pub fn close(id: String, v: Value, sender: Sender<RoomEventData>)
 pub fn start_queue(id: String, v: Value, sender: Sender<RoomEventData>)
  -> std::result::Result<(), Error>
 {
-    let data: StartQueueData = serde_json::from_value(v)?;
-    sender.try_send(RoomEventData::StartQueue(StartQueueData{id: data.id.clone(), action: data.action.clone()}));
+    let mut data: StartQueueData = serde_json::from_value(v)?;
+    if data.mode.is_empty() {
+        data.mode = DEFAULT_MODE.to_string();
+    }
+    sender.try_send(RoomEventData::StartQueue(data));
     Ok(())
 }
 
@@ -1721,6 +3107,15 @@ pub fn choose_ng_hero(id: String, v: Value, sender: Sender<RoomEventData>)
     Ok(())
 }
 
+pub fn choose_role(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: UserRoleData = serde_json::from_value(v)?;
+    data.id = id;
+    sender.try_send(RoomEventData::ChooseRole(data));
+    Ok(())
+}
+
 pub fn invite(id: String, v: Value, sender: Sender<RoomEventData>)
  -> std::result::Result<(), Error>
 {
@@ -1741,7 +3136,9 @@ pub fn leave(id: String, v: Value, sender: Sender<RoomEventData>)
 pub fn start_game(id: String, v: Value, sender: Sender<RoomEventData>)
  -> std::result::Result<(), Error>
 {
-    let data: StartGameData = serde_json::from_value(v)?;
+    let payload = signed_payload(&v);
+    let mut data: StartGameData = serde_json::from_value(v)?;
+    data.payload = payload;
     sender.try_send(RoomEventData::StartGame(data));
     Ok(())
 }
@@ -1749,7 +3146,10 @@ pub fn start_game(id: String, v: Value, sender: Sender<RoomEventData>)
 pub fn game_over(id: String, v: Value, sender: Sender<RoomEventData>)
  -> std::result::Result<(), Error>
 {
-    let data: GameOverData = serde_json::from_value(v)?;
+    let payload = signed_payload(&v);
+    let mut data: GameOverData = serde_json::from_value(v)?;
+    data.reporter = id;
+    data.payload = payload;
     sender.try_send(RoomEventData::GameOver(data));
     Ok(())
 }
@@ -1757,8 +3157,18 @@ pub fn game_over(id: String, v: Value, sender: Sender<RoomEventData>)
 pub fn game_info(id: String, v: Value, sender: Sender<RoomEventData>)
  -> std::result::Result<(), Error>
 {
-    let data: GameInfoData = serde_json::from_value(v)?;
-    sender.try_send(RoomEventData::GameInfo(data));
+    let payload = signed_payload(&v);
+    match serde_json::from_value::<GameInfoData>(v) {
+        Ok(mut data) => {
+            data.reporter = id;
+            data.payload = payload;
+            sender.try_send(RoomEventData::GameInfo(data));
+        },
+        Err(e) => {
+            // overflowing or missing stats are answered instead of dropped
+            sender.try_send(RoomEventData::BadReport(BadReportData {topic: format!("game/{}/res/game_info", id), reason: e.to_string()}));
+        }
+    }
     Ok(())
 }
 
@@ -1766,7 +3176,9 @@ pub fn game_info(id: String, v: Value, sender: Sender<RoomEventData>)
 pub fn game_close(id: String, v: Value, sender: Sender<RoomEventData>)
  -> std::result::Result<(), Error>
 {
-    let data: GameCloseData = serde_json::from_value(v)?;
+    let payload = signed_payload(&v);
+    let mut data: GameCloseData = serde_json::from_value(v)?;
+    data.payload = payload;
     sender.try_send(RoomEventData::GameClose(data));
     Ok(())
 }
@@ -1792,4 +3204,93 @@ pub fn server_dead(id: String, sender: Sender<RoomEventData>)
 {
     sender.try_send(RoomEventData::MainServerDead(DeadData{ServerDead: id}));
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn season_start(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let payload = signed_payload(&v);
+    let mut data: SeasonData = serde_json::from_value(v)?;
+    data.id = id;
+    data.payload = payload;
+    sender.try_send(RoomEventData::SeasonStart(data));
+    Ok(())
+}
+
+pub fn season_end(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let payload = signed_payload(&v);
+    let mut data: SeasonData = serde_json::from_value(v)?;
+    data.id = id;
+    data.payload = payload;
+    sender.try_send(RoomEventData::SeasonEnd(data));
+    Ok(())
+}
+
+pub fn season_history(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: SeasonHistoryData = serde_json::from_value(v)?;
+    data.id = id;
+    sender.try_send(RoomEventData::SeasonHistory(data));
+    Ok(())
+}
+
+pub fn rating_history(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: RatingHistoryData = serde_json::from_value(v)?;
+    data.id = id;
+    sender.try_send(RoomEventData::RatingHistory(data));
+    Ok(())
+}
+
+pub fn rollback(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let payload = signed_payload(&v);
+    let mut data: RollbackData = serde_json::from_value(v)?;
+    data.id = id;
+    data.payload = payload;
+    sender.try_send(RoomEventData::Rollback(data));
+    Ok(())
+}
+
+pub fn leaderboard_query(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: LeaderboardQueryData = serde_json::from_value(v)?;
+    if data.id == "" {
+        data.id = id;
+    }
+    sender.try_send(RoomEventData::LeaderboardQuery(data));
+    Ok(())
+}
+
+pub fn profile(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: ProfileData = serde_json::from_value(v)?;
+    data.id = id;
+    sender.try_send(RoomEventData::Profile(data));
+    Ok(())
+}
+
+pub fn history(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: MatchHistoryData = serde_json::from_value(v)?;
+    data.id = id;
+    sender.try_send(RoomEventData::MatchHistory(data));
+    Ok(())
+}
+
+pub fn hero_stats(id: String, v: Value, sender: Sender<RoomEventData>)
+ -> std::result::Result<(), Error>
+{
+    let mut data: HeroStatsQueryData = serde_json::from_value(v)?;
+    data.id = id;
+    sender.try_send(RoomEventData::HeroStatsQuery(data));
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use std::f64::consts::PI;
+
+pub const DEFAULT_RD: f32 = 350.0;
+pub const DEFAULT_VOL: f32 = 0.06;
+const SCALE: f64 = 173.7178;
+const EPSILON: f64 = 0.000001;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlickoRating {
+    pub rating: f32,
+    pub rd: f32,
+    pub vol: f32,
+}
+
+impl Default for GlickoRating {
+    fn default() -> GlickoRating {
+        GlickoRating { rating: 1000.0, rd: DEFAULT_RD, vol: DEFAULT_VOL }
+    }
+}
+
+// http://www.glicko.net/glicko/glicko2.pdf
+// Only rating differences matter, so ratings stay on the ng scale.
+pub struct Glicko2 {
+    pub tau: f32,
+}
+
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+}
+
+fn e(mu: f64, mu_j: f64, phi_j: f64) -> f64 {
+    1.0 / (1.0 + (-g(phi_j) * (mu - mu_j)).exp())
+}
+
+impl Glicko2 {
+    pub fn get_expected(&self, a: &GlickoRating, b: &GlickoRating) -> f32 {
+        e(a.rating as f64 / SCALE, b.rating as f64 / SCALE, b.rd as f64 / SCALE) as f32
+    }
+
+    // one rating period, results are (opponent, score) with score 1 win, 0.5 draw, 0 loss
+    pub fn update(&self, r: &GlickoRating, results: &Vec<(GlickoRating, f32)>) -> GlickoRating {
+        let mu = r.rating as f64 / SCALE;
+        let phi = r.rd as f64 / SCALE;
+        let sigma = r.vol as f64;
+        if results.len() == 0 {
+            let phi = (phi * phi + sigma * sigma).sqrt();
+            return GlickoRating { rating: r.rating, rd: (phi * SCALE) as f32, vol: r.vol };
+        }
+        let mut v_inv = 0.0;
+        let mut sum = 0.0;
+        for (o, s) in results {
+            let mu_j = o.rating as f64 / SCALE;
+            let phi_j = o.rd as f64 / SCALE;
+            let ex = e(mu, mu_j, phi_j);
+            v_inv += g(phi_j) * g(phi_j) * ex * (1.0 - ex);
+            sum += g(phi_j) * (*s as f64 - ex);
+        }
+        let v = 1.0 / v_inv;
+        let delta = v * sum;
+
+        // new volatility, Illinois algorithm
+        let tau = self.tau as f64;
+        let a = (sigma * sigma).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            ex * (delta * delta - phi * phi - v - ex) / (2.0 * (phi * phi + v + ex).powi(2)) - (x - a) / (tau * tau)
+        };
+        let mut big_a = a;
+        let mut big_b;
+        if delta * delta > phi * phi + v {
+            big_b = (delta * delta - phi * phi - v).ln();
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            big_b = a - k * tau;
+        }
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        while (big_b - big_a).abs() > EPSILON {
+            let c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(c);
+            if f_c * f_b <= 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = c;
+            f_b = f_c;
+        }
+        let new_sigma = (big_a / 2.0).exp();
+
+        let phi_star = (phi * phi + new_sigma * new_sigma).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * sum;
+        GlickoRating {
+            rating: (new_mu * SCALE) as f32,
+            rd: (new_phi * SCALE) as f32,
+            vol: new_sigma as f32,
+        }
+    }
+
+    // the other team is treated as one player with the mean rating and the rms deviation
+    pub fn composite(&self, team: &Vec<GlickoRating>) -> GlickoRating {
+        let n = team.len() as f32;
+        let rating = team.iter().map(|r| r.rating).sum::<f32>() / n;
+        let rd = (team.iter().map(|r| r.rd * r.rd).sum::<f32>() / n).sqrt();
+        GlickoRating { rating: rating, rd: rd, vol: DEFAULT_VOL }
+    }
+
+    pub fn compute_team(&self, winteam: &Vec<GlickoRating>, loseteam: &Vec<GlickoRating>)
+        -> (Vec<GlickoRating>, Vec<GlickoRating>) {
+        let win = self.composite(winteam);
+        let lose = self.composite(loseteam);
+        let wint = winteam.iter().map(|r| self.update(r, &vec![(lose, 1.0)])).collect();
+        let loset = loseteam.iter().map(|r| self.update(r, &vec![(win, 0.0)])).collect();
+        (wint, loset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glicko2() {
+        // example from the glicko-2 paper, shifted by -500 onto the ng scale
+        let g2 = Glicko2 {tau: 0.5};
+        let p = GlickoRating {rating: 1000.0, rd: 200.0, vol: 0.06};
+        let results = vec![
+            (GlickoRating {rating: 900.0, rd: 30.0, vol: 0.06}, 1.0),
+            (GlickoRating {rating: 1050.0, rd: 100.0, vol: 0.06}, 0.0),
+            (GlickoRating {rating: 1200.0, rd: 300.0, vol: 0.06}, 0.0),
+        ];
+        let r = g2.update(&p, &results);
+        println!("{:?}", r);
+        assert!((r.rating - 964.06).abs() < 0.1);
+        assert!((r.rd - 151.52).abs() < 0.1);
+        assert!((r.vol - 0.05999).abs() < 0.0001);
+
+        let wint = vec![p, p];
+        let loset = vec![GlickoRating {rating: 1000.0, rd: 50.0, vol: 0.06}; 2];
+        let (wt, lt) = g2.compute_team(&wint, &loset);
+        println!("win {:?}\nlose {:?}", wt, lt);
+        assert!(wt[0].rating - 1000.0 > 1000.0 - lt[0].rating);
+    }
+}
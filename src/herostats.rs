@@ -0,0 +1,128 @@
+use serde_derive::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+// bracket name that covers every rated game
+pub const ALL_BRACKETS: &str = "all";
+
+// one player's line of a rated game
+#[derive(Clone, Debug, Default)]
+pub struct HeroGame {
+    pub hero: String,
+    pub bracket: String,
+    pub win: bool,
+    pub level: u64,
+    pub damage: u64,
+    pub take_damage: u64,
+    pub heal: u64,
+    pub kill: u64,
+    pub death: u64,
+    pub assist: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HeroStat {
+    pub hero: String,
+    pub bracket: String,
+    pub games: u32,
+    pub wins: u32,
+    // share of all picks in the bracket
+    pub pick_rate: f32,
+    pub win_rate: f32,
+    pub avg_level: f32,
+    pub avg_damage: f32,
+    pub avg_take_damage: f32,
+    pub avg_heal: f32,
+    pub avg_kill: f32,
+    pub avg_death: f32,
+    pub avg_assist: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Totals {
+    games: u32,
+    wins: u32,
+    level: u64,
+    damage: u64,
+    take_damage: u64,
+    heal: u64,
+    kill: u64,
+    death: u64,
+    assist: u64,
+}
+
+impl Totals {
+    fn add(&mut self, g: &HeroGame) {
+        self.games += 1;
+        if g.win {
+            self.wins += 1;
+        }
+        self.level += g.level;
+        self.damage += g.damage;
+        self.take_damage += g.take_damage;
+        self.heal += g.heal;
+        self.kill += g.kill;
+        self.death += g.death;
+        self.assist += g.assist;
+    }
+}
+
+// every game counts toward its own bracket and toward ALL_BRACKETS
+pub fn aggregate(games: &Vec<HeroGame>) -> Vec<HeroStat> {
+    let mut totals: BTreeMap<(String, String), Totals> = BTreeMap::new();
+    let mut picks: BTreeMap<String, u32> = BTreeMap::new();
+    for g in games {
+        for b in &[g.bracket.as_str(), ALL_BRACKETS] {
+            totals.entry((b.to_string(), g.hero.clone())).or_default().add(g);
+            *picks.entry(b.to_string()).or_default() += 1;
+        }
+    }
+    totals.iter().map(|((bracket, hero), t)| {
+        let n = t.games as f32;
+        HeroStat {
+            hero: hero.clone(),
+            bracket: bracket.clone(),
+            games: t.games,
+            wins: t.wins,
+            pick_rate: n / picks[bracket] as f32,
+            win_rate: t.wins as f32 / n,
+            avg_level: t.level as f32 / n,
+            avg_damage: t.damage as f32 / n,
+            avg_take_damage: t.take_damage as f32 / n,
+            avg_heal: t.heal as f32 / n,
+            avg_kill: t.kill as f32 / n,
+            avg_death: t.death as f32 / n,
+            avg_assist: t.assist as f32 / n,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let game = |hero: &str, bracket: &str, win: bool, damage: u64| HeroGame {
+            hero: hero.to_string(), bracket: bracket.to_string(), win: win, damage: damage, ..Default::default()
+        };
+        let games = vec![
+            game("axe", "Gold", true, 1000),
+            game("axe", "Gold", false, 3000),
+            game("lina", "Gold", true, 2000),
+            game("lina", "Silver", false, 500),
+        ];
+        let res = aggregate(&games);
+        for s in &res {
+            println!("{:?}", s);
+        }
+        let axe = res.iter().find(|s| s.hero == "axe" && s.bracket == "Gold").unwrap();
+        assert_eq!(axe.games, 2);
+        assert_eq!(axe.win_rate, 0.5);
+        assert_eq!(axe.avg_damage, 2000.0);
+        assert!((axe.pick_rate - 2.0 / 3.0).abs() < 1e-6);
+        let lina = res.iter().find(|s| s.hero == "lina" && s.bracket == ALL_BRACKETS).unwrap();
+        assert_eq!(lina.games, 2);
+        assert_eq!(lina.pick_rate, 0.5);
+        assert_eq!(res.iter().filter(|s| s.bracket == "Silver").count(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use serde_json::{self, Value};
+use crossbeam_channel::{bounded, never, Sender, Receiver};
+use failure::Error;
+use log::{info, error};
+
+use crate::config::HttpConfig;
+use crate::auth::token_eq;
+use crate::metrics;
+use crate::event_room::{RoomEventData, HttpRequestData};
+
+const READ_PATHS: &[&str] = &["/users", "/rooms", "/queue", "/prestart", "/games", "/leaderboard"];
+const ADMIN_PATHS: &[&str] = &["/admin/kick", "/admin/cancel_game", "/admin/reset", "/admin/leaderboard"];
+
+struct Request {
+    method: String,
+    path: String,
+    params: BTreeMap<String, String>,
+    token: String,
+}
+
+// query string and json body fields end up in the same params map
+fn read_request(stream: &TcpStream) -> Result<Request, Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("").to_string();
+    let mut length = 0;
+    let mut token = String::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim() == "" {
+            break;
+        }
+        let mut kv = header.splitn(2, ':');
+        let key = kv.next().unwrap_or("").trim().to_lowercase();
+        let value = kv.next().unwrap_or("").trim().to_string();
+        if key == "content-length" {
+            length = value.parse().unwrap_or(0);
+        } else if key == "authorization" {
+            token = value.trim_start_matches("Bearer ").to_string();
+        }
+    }
+    let mut params = BTreeMap::new();
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_string(), target[i+1..].to_string()),
+        None => (target.clone(), "".to_string()),
+    };
+    for (k, v) in url::form_urlencoded::parse(query.as_bytes()) {
+        params.insert(k.to_string(), v.to_string());
+    }
+    if length > 0 {
+        let mut body = vec![0; std::cmp::min(length, 65536)];
+        reader.read_exact(&mut body)?;
+        if let Ok(Value::Object(m)) = serde_json::from_slice::<Value>(&body) {
+            for (k, v) in m {
+                let v = match v {
+                    Value::String(s) => s,
+                    v => v.to_string(),
+                };
+                params.insert(k, v);
+            }
+        }
+    }
+    Ok(Request {method: method, path: path, params: params, token: token})
+}
+
+fn write_response(stream: &TcpStream, code: u16, body: &str) -> Result<(), Error> {
+    write_typed(stream, code, "application/json", body)
+}
+
+fn write_typed(mut stream: &TcpStream, code: u16, content_type: &str, body: &str) -> Result<(), Error> {
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    };
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code, reason, content_type, body.len(), body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn handle_conn(stream: TcpStream, admin_token: &str, tx: &Sender<RoomEventData>) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let req = read_request(&stream)?;
+    let path = req.path.trim_end_matches('/');
+    // served from the atomics, so it answers even when the room thread is stuck
+    if path == "/metrics" {
+        return write_typed(&stream, 200, "text/plain; version=0.0.4", &metrics::render());
+    }
+    if ADMIN_PATHS.contains(&path) {
+        if req.method != "POST" {
+            return write_response(&stream, 405, r#"{"msg":"use POST"}"#);
+        }
+        if admin_token == "" {
+            return write_response(&stream, 403, r#"{"msg":"admin api disabled"}"#);
+        }
+        if !token_eq(&req.token, admin_token) {
+            return write_response(&stream, 401, r#"{"msg":"bad token"}"#);
+        }
+    } else if READ_PATHS.contains(&path) {
+        if req.method != "GET" {
+            return write_response(&stream, 405, r#"{"msg":"use GET"}"#);
+        }
+    } else {
+        return write_response(&stream, 404, r#"{"msg":"not found"}"#);
+    }
+    let (reply, rx): (Sender<(u16, String)>, Receiver<(u16, String)>) = bounded(1);
+    tx.try_send(RoomEventData::Http(HttpRequestData {path: path.to_string(), params: req.params, reply: reply}))?;
+    match rx.recv_timeout(Duration::from_secs(5)) {
+        Ok((code, body)) => write_response(&stream, code, &body),
+        Err(_) => write_response(&stream, 504, r#"{"msg":"timeout"}"#),
+    }
+}
+
+// requests are handed to the main loop, which forwards them to the live room thread
+pub fn HandleHttpRequest(config: HttpConfig) -> Result<Receiver<RoomEventData>, Error> {
+    if config.port == 0 {
+        return Ok(never());
+    }
+    let (tx, rx): (Sender<RoomEventData>, Receiver<RoomEventData>) = bounded(1000);
+    // a taken port leaves the server running without http instead of stopping it
+    let listener = match TcpListener::bind((config.bind.as_str(), config.port)) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("http bind {}:{}: {}", config.bind, config.port, e);
+            return Ok(never());
+        }
+    };
+    info!("http listen on {}:{}", config.bind, config.port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let tx = tx.clone();
+                let token = config.admin_token.clone();
+                thread::spawn(move || {
+                    if let Err(msg) = handle_conn(stream, &token, &tx) {
+                        error!("http: {:?}", msg);
+                    }
+                });
+            }
+        }
+    });
+    Ok(rx)
+}
@@ -0,0 +1,118 @@
+use serde_derive::{Serialize, Deserialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    // 1 based
+    pub rank: usize,
+    pub id: String,
+    pub score: i16,
+}
+
+// players sorted by score, ties broken by id
+#[derive(Clone, Debug, Default)]
+pub struct Leaderboard {
+    order: BTreeSet<(Reverse<i16>, String)>,
+    scores: HashMap<String, i16>,
+}
+
+impl Leaderboard {
+    pub fn update(&mut self, id: &str, score: i16) {
+        if let Some(old) = self.scores.insert(id.to_string(), score) {
+            self.order.remove(&(Reverse(old), id.to_string()));
+        }
+        self.order.insert((Reverse(score), id.to_string()));
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        if let Some(old) = self.scores.remove(id) {
+            self.order.remove(&(Reverse(old), id.to_string()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn rank(&self, id: &str) -> Option<usize> {
+        let score = self.scores.get(id)?;
+        Some(self.order.range(..(Reverse(*score), id.to_string())).count() + 1)
+    }
+
+    pub fn top(&self, offset: usize, count: usize) -> Vec<LeaderboardEntry> {
+        self.order.iter().enumerate().skip(offset).take(count)
+            .map(|(i, (s, id))| LeaderboardEntry { rank: i + 1, id: id.clone(), score: s.0 })
+            .collect()
+    }
+
+    // the player with up to `count` players on each side
+    pub fn around(&self, id: &str, count: usize) -> Vec<LeaderboardEntry> {
+        match self.rank(id) {
+            Some(rank) => {
+                let start = if rank > count + 1 { rank - count - 1 } else { 0 };
+                self.top(start, count * 2 + 1)
+            },
+            None => vec![],
+        }
+    }
+}
+
+// one board per mode ("ng" or "rk"), region ("" for global) and season
+#[derive(Clone, Debug, Default)]
+pub struct Leaderboards {
+    boards: BTreeMap<(String, String, u32), Leaderboard>,
+}
+
+impl Leaderboards {
+    pub fn update(&mut self, mode: &str, region: &str, season: u32, id: &str, score: i16) {
+        self.boards.entry((mode.to_string(), "".to_string(), season)).or_default().update(id, score);
+        if region != "" {
+            self.boards.entry((mode.to_string(), region.to_string(), season)).or_default().update(id, score);
+        }
+    }
+
+    pub fn remove(&mut self, mode: &str, region: &str, season: u32, id: &str) {
+        for r in &["", region] {
+            if let Some(b) = self.boards.get_mut(&(mode.to_string(), r.to_string(), season)) {
+                b.remove(id);
+            }
+        }
+    }
+
+    pub fn get(&self, mode: &str, region: &str, season: u32) -> Option<&Leaderboard> {
+        self.boards.get(&(mode.to_string(), region.to_string(), season))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaderboard() {
+        let mut b = Leaderboard::default();
+        for (i, s) in [1000, 1200, 900, 1100, 1200].iter().enumerate() {
+            b.update(&format!("p{}", i), *s);
+        }
+        let top = b.top(0, 3);
+        println!("{:?}", top);
+        assert_eq!(top[0].id, "p1");
+        assert_eq!(top[1].id, "p4");
+        assert_eq!(top[2].id, "p3");
+        assert_eq!(b.rank("p2"), Some(5));
+
+        b.update("p2", 1300);
+        assert_eq!(b.rank("p2"), Some(1));
+        assert_eq!(b.len(), 5);
+        let around = b.around("p3", 1);
+        println!("{:?}", around);
+        assert_eq!(around.iter().map(|e| e.rank).collect::<Vec<_>>(), vec![3, 4, 5]);
+
+        let mut boards = Leaderboards::default();
+        boards.update("rk", "eu", 1, "p0", 1000);
+        boards.update("rk", "us", 1, "p1", 1100);
+        assert_eq!(boards.get("rk", "", 1).unwrap().len(), 2);
+        assert_eq!(boards.get("rk", "eu", 1).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeSet;
+
+// one player's rating change in a game, as read back from rating_ledger
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LedgerRow {
+    pub id: String,
+    pub mode: String,
+    pub ng_delta: i16,
+    pub rk_delta: i16,
+    pub rd_before: f32,
+    // null in rows written before they were recorded
+    pub vol_before: Option<f32>,
+    pub tier_before: Option<u16>,
+}
+
+// games that have been rolled back, seeded from void_game at startup
+#[derive(Clone, Debug, Default)]
+pub struct VoidGames {
+    games: BTreeSet<u32>,
+}
+
+impl VoidGames {
+    pub fn insert(&mut self, game: u32) {
+        self.games.insert(game);
+    }
+
+    // taken before the ledger is read, so a second rollback of the same game is turned away
+    pub fn claim(&mut self, game: u32) -> Result<(), String> {
+        if game == 0 || !self.games.insert(game) {
+            return Err(format!("game {} is void", game));
+        }
+        Ok(())
+    }
+
+    // keeps the first row per player so a game is reversed at most once for each of them.
+    // Without rows the claim is dropped, the game can be rolled back once its ledger is written
+    pub fn rows(&mut self, game: u32, rows: Vec<LedgerRow>) -> Result<Vec<LedgerRow>, String> {
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+        let rows: Vec<LedgerRow> = rows.into_iter().filter(|r| seen.insert(r.id.clone())).collect();
+        if rows.is_empty() {
+            self.games.remove(&game);
+            return Err(format!("game {} has no rating changes", game));
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollback_twice() {
+        let mut void = VoidGames::default();
+        let row = LedgerRow {id: "a".to_string(), ng_delta: 20, rk_delta: 15, ..Default::default()};
+        // the same change written twice, as both instances used to do
+        let ledger = vec![row.clone(), row.clone()];
+        let mut ng: i16 = 1020;
+        for _ in 0..2 {
+            if void.claim(7).is_ok() {
+                for r in void.rows(7, ledger.clone()).unwrap() {
+                    ng -= r.ng_delta;
+                }
+            }
+        }
+        println!("ng {}", ng);
+        assert_eq!(ng, 1000);
+        assert!(void.claim(7).is_err());
+        assert!(void.claim(0).is_err());
+
+        // ledger not written yet, the game stays open for a later rollback
+        assert!(void.claim(8).is_ok());
+        assert!(void.rows(8, vec![]).is_err());
+        assert!(void.claim(8).is_ok());
+    }
+}
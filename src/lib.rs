@@ -0,0 +1,12 @@
+pub mod elo;
+pub mod config;
+pub mod matchmaker;
+pub mod glicko2;
+pub mod trueskill;
+pub mod rating;
+pub mod tier;
+pub mod leaderboard;
+pub mod herostats;
+pub mod metrics;
+pub mod auth;
+pub mod ledger;
@@ -5,7 +5,9 @@ mod event_member;
 mod event_room;
 mod room;
 mod msg;
-mod elo;
+mod http;
+
+use erps::{elo, config, matchmaker, glicko2, trueskill, rating, tier, leaderboard, herostats, metrics, auth, ledger};
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -34,6 +36,7 @@ use crate::event_room::RoomEventData;
 use crate::event_room::SqlData;
 use crate::event_room::QueueData;
 use crate::msg::*;
+use crate::config::Config;
 
 fn generate_client_id() -> String {
     let s = format!("Elo_Pub_{}", Uuid::new_v4());
@@ -87,6 +90,12 @@ fn main() -> std::result::Result<(), Error> {
             .long("backup")
             .takes_value(true)
             .help("backup"),
+        ).arg(
+            Arg::with_name("CONFIG")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .help("game mode config file (json)"),
         ).get_matches();
 
     let server_addr = matches.value_of("SERVER").unwrap_or("172.104.78.55").to_owned();
@@ -97,6 +106,14 @@ fn main() -> std::result::Result<(), Error> {
         .unwrap_or("Elo Rank Server".to_owned());
     let mut isBackup: bool = matches.value_of("BACKUP").unwrap_or("false").to_owned().parse().unwrap();
     println!("Backup: {}", isBackup);
+    let mut config = match matches.value_of("CONFIG") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if config.server_key == "" {
+        warn!("server_key is not set, a backup will not accept reports for games started here");
+        config.server_key = Uuid::new_v4().to_simple().to_string();
+    }
     let mut mqtt_options = MqttOptions::new(client_id.as_str(), server_addr.as_str(), server_port.parse::<u16>()?);
     mqtt_options = mqtt_options.set_keep_alive(100);
     mqtt_options = mqtt_options.set_request_channel_capacity(10000);
@@ -110,8 +127,14 @@ fn main() -> std::result::Result<(), Error> {
     mqtt_client.subscribe("member/+/send/login", QoS::AtMostOnce)?;
     mqtt_client.subscribe("member/+/send/logout", QoS::AtMostOnce)?;
     mqtt_client.subscribe("member/+/send/choose_hero", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("member/+/send/choose_role", QoS::AtMostOnce)?;
     mqtt_client.subscribe("member/+/send/status", QoS::AtMostOnce)?;
     mqtt_client.subscribe("member/+/send/reconnect", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("member/+/send/season_history", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("member/+/send/rating_history", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("member/+/send/profile", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("member/+/send/history", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("stats/+/send/hero", QoS::AtMostOnce)?;
 
     mqtt_client.subscribe("room/+/send/create", QoS::AtMostOnce)?;
     mqtt_client.subscribe("room/+/send/close", QoS::AtMostOnce)?;
@@ -133,6 +156,12 @@ fn main() -> std::result::Result<(), Error> {
     mqtt_client.subscribe("game/+/send/choose", QoS::AtMostOnce)?;
     mqtt_client.subscribe("game/+/send/leave", QoS::AtMostOnce)?;
     mqtt_client.subscribe("game/+/send/exit", QoS::AtMostOnce)?;
+
+    mqtt_client.subscribe("leaderboard/+/send/query", QoS::AtMostOnce)?;
+
+    mqtt_client.subscribe("admin/+/send/season_start", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("admin/+/send/season_end", QoS::AtMostOnce)?;
+    mqtt_client.subscribe("admin/+/send/rollback", QoS::AtMostOnce)?;
     
     let mut isServerLive = true;
     
@@ -167,11 +196,14 @@ fn main() -> std::result::Result<(), Error> {
                                     isBackup = false;
                                 }
                                 if d.topic.len() > 2 {
+                                    let topic = d.topic.clone();
                                     let msg_res = mqtt_client.publish(d.topic, QoS::AtMostOnce, false, d.msg);
                                     match msg_res {
                                         Ok(_) =>{},
                                         Err(x) => {
-                                            panic!("??? {}", x);
+                                            // counted and dropped, the next message gets its own try
+                                            metrics::MQTT_PUBLISH_FAILURES.inc();
+                                            warn!("mqtt publish {}: {}", topic, x);
                                         }
                                     }
                                 }
@@ -207,6 +239,7 @@ fn main() -> std::result::Result<(), Error> {
     let rejoin = Regex::new(r"\w+/(\w+)/send/join")?;
     let reset = Regex::new(r"reset")?;
     let rechoosehero = Regex::new(r"\w+/(\w+)/send/choose_hero")?;
+    let rechooserole = Regex::new(r"\w+/(\w+)/send/choose_role")?;
     let releave = Regex::new(r"\w+/(\w+)/send/leave")?;
     let restart_game = Regex::new(r"\w+/(\w+)/send/start_game")?;
     let regame_over = Regex::new(r"\w+/(\w+)/send/game_over")?;
@@ -214,10 +247,23 @@ fn main() -> std::result::Result<(), Error> {
     let regame_close = Regex::new(r"\w+/(\w+)/send/game_close")?;
     let restatus = Regex::new(r"\w+/(\w+)/send/status")?;
     let rereconnect = Regex::new(r"\w+/(\w+)/send/reconnect")?;
+    let reseason_start = Regex::new(r"\w+/(\w+)/send/season_start")?;
+    let reseason_end = Regex::new(r"\w+/(\w+)/send/season_end")?;
+    let reseason_history = Regex::new(r"\w+/(\w+)/send/season_history")?;
+    let rerating_history = Regex::new(r"\w+/(\w+)/send/rating_history")?;
+    let rerollback = Regex::new(r"\w+/(\w+)/send/rollback")?;
+    let releaderboard = Regex::new(r"leaderboard/(\w+)/send/query")?;
+    let reprofile = Regex::new(r"\w+/(\w+)/send/profile")?;
+    let rehistory = Regex::new(r"\w+/(\w+)/send/history$")?;
+    let rehero_stats = Regex::new(r"stats/(\w+)/send/hero")?;
     
     //let mut QueueSender: Sender<QueueData>;
-    let mut sender1: Sender<SqlData> = event_room::HandleSqlRequest(pool.clone())?;
-    let (mut sender, mut QueueSender): (Sender<RoomEventData>, Sender<QueueData>) = event_room::init(tx.clone(), sender1.clone(), pool.clone(), None, isBackup)?;
+    let mut sender1: Sender<SqlData> = event_room::HandleSqlRequest(pool.clone(), config.clone())?;
+    if !isBackup {
+        event_room::HandleHeroStats(pool.clone(), config.clone())?;
+    }
+    let http_rx = http::HandleHttpRequest(config.http.clone())?;
+    let (mut sender, mut QueueSender): (Sender<RoomEventData>, Sender<QueueData>) = event_room::init(tx.clone(), sender1.clone(), pool.clone(), None, isBackup, config.clone())?;
     let update = tick(Duration::from_millis(500));
     let mut is_live = true;
     let mut sender = sender.clone();
@@ -228,6 +274,11 @@ fn main() -> std::result::Result<(), Error> {
         
         select! {
             
+            recv(http_rx) -> d => {
+                if let Ok(d) = d {
+                    sender.try_send(d);
+                }
+            },
             recv (check_server) -> _ => {
                 if isBackup {
                     //println!("isServerLive {}", isServerLive);
@@ -238,6 +289,7 @@ fn main() -> std::result::Result<(), Error> {
                         println!("Main Server dead!!");
                         event_room::server_dead("0".to_string(), sender.clone())?;
                         isBackup = false;
+                        event_room::HandleHeroStats(pool.clone(), config.clone())?;
                     }
                 }
             },
@@ -245,7 +297,7 @@ fn main() -> std::result::Result<(), Error> {
                 if !is_live{
                     println!("Reconnect!");
                     
-                    let (mut sender1, mut QueueSender1): (Sender<RoomEventData>, Sender<QueueData>) = event_room::init(tx.clone(), sender1.clone(), pool.clone(), Some(QueueSender.clone()), isBackup)?;
+                    let (mut sender1, mut QueueSender1): (Sender<RoomEventData>, Sender<QueueData>) = event_room::init(tx.clone(), sender1.clone(), pool.clone(), Some(QueueSender.clone()), isBackup, config.clone())?;
                     sender = sender1.clone();
                     QueueSender = QueueSender1.clone();
                     
@@ -287,6 +339,11 @@ fn main() -> std::result::Result<(), Error> {
                                     let userid = cap[1].to_string();
                                     //info!("choose ng hero: userid: {} json: {:?}", userid, v);
                                     event_room::choose_ng_hero(userid, v, sender.clone())?;
+                                } else if rechooserole.is_match(topic_name) {
+                                    let cap = rechooserole.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    //info!("choose role: userid: {} json: {:?}", userid, v);
+                                    event_room::choose_role(userid, v, sender.clone())?;
                                 } else if rejoin.is_match(topic_name) {
                                     let cap = rejoin.captures(topic_name).unwrap();
                                     let userid = cap[1].to_string();
@@ -367,6 +424,42 @@ fn main() -> std::result::Result<(), Error> {
                                     let userid = cap[1].to_string();
                                     //info!("reconnect: userid: {} json: {:?}", userid, v);
                                     event_room::reconnect(userid, v, sender.clone())?;
+                                } else if reseason_start.is_match(topic_name) {
+                                    let cap = reseason_start.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::season_start(userid, v, sender.clone())?;
+                                } else if reseason_end.is_match(topic_name) {
+                                    let cap = reseason_end.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::season_end(userid, v, sender.clone())?;
+                                } else if reseason_history.is_match(topic_name) {
+                                    let cap = reseason_history.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::season_history(userid, v, sender.clone())?;
+                                } else if rerating_history.is_match(topic_name) {
+                                    let cap = rerating_history.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::rating_history(userid, v, sender.clone())?;
+                                } else if rerollback.is_match(topic_name) {
+                                    let cap = rerollback.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::rollback(userid, v, sender.clone())?;
+                                } else if releaderboard.is_match(topic_name) {
+                                    let cap = releaderboard.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::leaderboard_query(userid, v, sender.clone())?;
+                                } else if reprofile.is_match(topic_name) {
+                                    let cap = reprofile.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::profile(userid, v, sender.clone())?;
+                                } else if rehistory.is_match(topic_name) {
+                                    let cap = rehistory.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::history(userid, v, sender.clone())?;
+                                } else if rehero_stats.is_match(topic_name) {
+                                    let cap = rehero_stats.captures(topic_name).unwrap();
+                                    let userid = cap[1].to_string();
+                                    event_room::hero_stats(userid, v, sender.clone())?;
                                 }
                             } else {
                                 warn!("Json Parser error");
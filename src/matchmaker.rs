@@ -0,0 +1,450 @@
+use serde_derive::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+use failure::Error;
+
+use crate::config::ModeConfig;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct QueueRoomData {
+    pub rid: u32,
+    pub gid: u32,
+    pub user_len: i16,
+    pub avg_ng: i16,
+    pub avg_rk: i16,
+    pub ready: i8,
+    pub queue_cnt: i16,
+    pub mode: String,
+    pub users: Vec<String>,
+    pub roles: Vec<Vec<String>>,
+    pub avg_rd: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReadyGroupData {
+    pub gid: u32,
+    pub rid: Vec<u32>,
+    pub user_len: i16,
+    pub avg_ng: i16,
+    pub avg_rk: i16,
+    pub game_status: u16,
+    pub queue_cnt: i16,
+    pub max_party: i16,
+    pub min_party: i16,
+    pub users: Vec<String>,
+    pub roles: Vec<Vec<String>>,
+    pub avg_rd: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReadyGameData {
+    pub gid: Vec<u32>,
+    pub group: Vec<Vec<u32>>,
+    pub team_len: usize,
+    pub roles: BTreeMap<String, String>,
+}
+
+pub trait Matchmaker {
+    /// Runs one matching pass over the rooms queued in this mode and returns the
+    /// games that are ready for prestart. Rooms in a returned game are no longer
+    /// tracked by the matchmaker and should be dropped from the queue.
+    fn find_matches(&mut self, rooms: &BTreeMap<u32, Rc<RefCell<QueueRoomData>>>) -> Vec<ReadyGameData>;
+
+    /// Called when a room leaves the queue, releases the group it was waiting in.
+    fn remove_room(&mut self, rid: u32, rooms: &BTreeMap<u32, Rc<RefCell<QueueRoomData>>>);
+}
+
+// Assigns every player a slot of the role template, trying each player's
+// preferred roles in order. An empty preference list means any role.
+// Returns the template index per player, None if the roles can't be filled.
+pub fn assign_roles(prefs: &Vec<Vec<String>>, template: &Vec<String>) -> Option<Vec<usize>> {
+    if prefs.len() > template.len() {
+        return None;
+    }
+    fn try_assign(p: usize, prefs: &Vec<Vec<String>>, template: &Vec<String>,
+        slot_owner: &mut Vec<Option<usize>>, seen: &mut Vec<bool>) -> bool {
+        let mut slots: Vec<usize> = vec![];
+        if prefs[p].len() == 0 {
+            slots = (0..template.len()).collect();
+        } else {
+            for role in &prefs[p] {
+                for (i, t) in template.iter().enumerate() {
+                    if t == role {
+                        slots.push(i);
+                    }
+                }
+            }
+        }
+        for i in slots {
+            if seen[i] {
+                continue;
+            }
+            seen[i] = true;
+            let owner = slot_owner[i];
+            let free = match owner {
+                None => true,
+                Some(o) => try_assign(o, prefs, template, slot_owner, seen),
+            };
+            if free {
+                slot_owner[i] = Some(p);
+                return true;
+            }
+        }
+        false
+    }
+    let mut slot_owner: Vec<Option<usize>> = vec![None; template.len()];
+    for p in 0..prefs.len() {
+        let mut seen = vec![false; template.len()];
+        if !try_assign(p, prefs, template, &mut slot_owner, &mut seen) {
+            return None;
+        }
+    }
+    let mut res = vec![0; prefs.len()];
+    for (i, o) in slot_owner.iter().enumerate() {
+        if let Some(p) = o {
+            res[*p] = i;
+        }
+    }
+    Some(res)
+}
+
+pub fn create_matchmaker(mode: &ModeConfig) -> Result<Box<dyn Matchmaker>, Error> {
+    match mode.matchmaker.as_str() {
+        "greedy" => Ok(Box::new(GreedyMatchmaker::new(mode.clone()))),
+        name => Err(failure::err_msg(format!("unknown matchmaker {}", name))),
+    }
+}
+
+// Fills teams room by room in rid order, then pairs up teams whose average
+// score is close enough. The allowed gap grows with queue_cnt every pass.
+pub struct GreedyMatchmaker {
+    pub mode: ModeConfig,
+    pub ready_groups: BTreeMap<u32, Rc<RefCell<ReadyGroupData>>>,
+    pub group_id: u32,
+}
+
+impl GreedyMatchmaker {
+    pub fn new(mode: ModeConfig) -> GreedyMatchmaker {
+        GreedyMatchmaker {
+            mode: mode,
+            ready_groups: BTreeMap::new(),
+            group_id: 0,
+        }
+    }
+
+    // score range a room is allowed to search, widened for uncertain ratings
+    fn search_range(&self, queue_cnt: i16, rd: f32) -> i16 {
+        self.mode.score_interval * queue_cnt + (self.mode.rd_search_scale * rd) as i16
+    }
+
+    fn room_ng(&self, r: &QueueRoomData) -> i16 {
+        r.avg_ng + self.mode.premade_bonus * (r.user_len - 1)
+    }
+
+    // a large party and a solo player can not share a team or a game when solo_party_limit is set
+    fn party_mix_ok(&self, max_a: i16, min_a: i16, max_b: i16, min_b: i16) -> bool {
+        let limit = self.mode.solo_party_limit;
+        if limit <= 0 || max_a == 0 || max_b == 0 {
+            return true;
+        }
+        !((max_a >= limit && min_b == 1) || (max_b >= limit && min_a == 1))
+    }
+
+    fn roles_ok(&self, g: &ReadyGroupData, v: &QueueRoomData) -> bool {
+        if self.mode.role_template.len() == 0 {
+            return true;
+        }
+        let mut prefs = g.roles.clone();
+        prefs.extend(v.roles.iter().cloned());
+        assign_roles(&prefs, &self.mode.role_template).is_some()
+    }
+
+    fn add_members(g: &mut ReadyGroupData, v: &QueueRoomData) {
+        let size = v.user_len;
+        // called after user_len already counts this room
+        if g.user_len > 0 {
+            g.avg_rd = (g.avg_rd * (g.user_len - size) as f32 + v.avg_rd * size as f32) / g.user_len as f32;
+        }
+        if g.max_party < size {
+            g.max_party = size;
+        }
+        if g.min_party == 0 || g.min_party > size {
+            g.min_party = size;
+        }
+        g.users.extend(v.users.iter().cloned());
+        g.roles.extend(v.roles.iter().cloned());
+    }
+
+    fn make_groups(&mut self, rooms: &BTreeMap<u32, Rc<RefCell<QueueRoomData>>>) {
+        let team_size = self.mode.team_size;
+        let mut g: ReadyGroupData = Default::default();
+        let mut id: Vec<u32> = vec![];
+        for v in rooms.values() {
+            let v_ng = self.room_ng(&v.borrow());
+            let v_len = v.borrow().user_len;
+            let v_range = self.search_range(v.borrow().queue_cnt, v.borrow().avg_rd);
+            if g.user_len > 0 && g.user_len < team_size && (g.avg_ng + v_range) < v_ng {
+                for r in g.rid {
+                    id.push(r);
+                }
+                g = Default::default();
+                g.rid.push(v.borrow().rid);
+                let ng = (g.avg_ng * g.user_len + v_ng * v_len) as i16 / (g.user_len + v_len) as i16;
+                g.avg_ng = ng;
+                g.user_len += v_len;
+                GreedyMatchmaker::add_members(&mut g, &v.borrow());
+                v.borrow_mut().ready = 1;
+                v.borrow_mut().gid = self.group_id + 1;
+                v.borrow_mut().queue_cnt += 1;
+            }
+
+            if v.borrow().ready == 0 &&
+                v_len + g.user_len <= team_size &&
+                self.party_mix_ok(g.max_party, g.min_party, v_len, v_len) &&
+                self.roles_ok(&g, &v.borrow()) {
+
+                let difference: i16 = i16::abs(v_ng - g.avg_ng);
+                if g.avg_ng == 0 || difference <= v_range {
+                    g.rid.push(v.borrow().rid);
+                    let ng;
+                    if g.user_len + v_len > 0 {
+                        ng = (g.avg_ng * g.user_len + v_ng * v_len) as i16 / (g.user_len + v_len) as i16;
+                    } else {
+                        g = Default::default();
+                        continue;
+                    }
+                    g.avg_ng = ng;
+                    g.user_len += v_len;
+                    GreedyMatchmaker::add_members(&mut g, &v.borrow());
+                    v.borrow_mut().ready = 1;
+                    v.borrow_mut().gid = self.group_id + 1;
+                }
+                else {
+                    v.borrow_mut().queue_cnt += 1;
+                }
+            }
+            if g.user_len == team_size {
+                self.group_id += 1;
+                g.gid = self.group_id;
+                g.queue_cnt = 1;
+                self.ready_groups.insert(self.group_id, Rc::new(RefCell::new(g.clone())));
+                g = Default::default();
+            }
+        }
+        if g.user_len < team_size {
+            for r in g.rid {
+                let room = rooms.get(&r);
+                if let Some(room) = room {
+                    room.borrow_mut().ready = 0;
+                    room.borrow_mut().gid = 0;
+                }
+            }
+            for r in id {
+                let room = rooms.get(&r);
+                if let Some(room) = room {
+                    room.borrow_mut().ready = 0;
+                    room.borrow_mut().gid = 0;
+                }
+            }
+        }
+    }
+
+    fn make_games(&mut self) -> Vec<ReadyGameData> {
+        let match_size = self.mode.match_size;
+        let mut res: Vec<ReadyGameData> = vec![];
+        let mut fg: ReadyGameData = Default::default();
+        let mut total_ng: i16 = 0;
+        let mut max_party: i16 = 0;
+        let mut min_party: i16 = 0;
+        let mut rm_ids: Vec<u32> = vec![];
+        for (id, rg) in &self.ready_groups {
+            if rg.borrow().game_status == 0 && fg.team_len < match_size {
+                if total_ng == 0 {
+                    total_ng += rg.borrow().avg_ng as i16;
+                    max_party = rg.borrow().max_party;
+                    min_party = rg.borrow().min_party;
+                    fg.group.push(rg.borrow().rid.clone());
+                    fg.gid.push(*id);
+                    fg.team_len += 1;
+                    continue;
+                }
+
+                let mut difference = 0;
+                if fg.team_len > 0 {
+                    difference = i16::abs(rg.borrow().avg_ng as i16 - total_ng/fg.team_len as i16);
+                }
+                // prefer opponents with a similar party composition
+                difference += self.mode.party_size_penalty * i16::abs(rg.borrow().max_party - max_party);
+                let mix_ok = self.party_mix_ok(max_party, min_party, rg.borrow().max_party, rg.borrow().min_party);
+                if mix_ok && difference <= self.search_range(rg.borrow().queue_cnt, rg.borrow().avg_rd) {
+                    total_ng += rg.borrow().avg_ng as i16;
+                    fg.group.push(rg.borrow().rid.clone());
+                    fg.team_len += 1;
+                    fg.gid.push(*id);
+                }
+                else {
+                    rg.borrow_mut().queue_cnt += 1;
+                }
+            }
+            if fg.team_len == match_size {
+                for id in &fg.gid {
+                    rm_ids.push(*id);
+                    let rg = self.ready_groups.get(id).unwrap().borrow();
+                    if let Some(slots) = assign_roles(&rg.roles, &self.mode.role_template) {
+                        for (i, u) in rg.users.iter().enumerate() {
+                            if self.mode.role_template.len() > 0 {
+                                fg.roles.insert(u.clone(), self.mode.role_template[slots[i]].clone());
+                            }
+                        }
+                    }
+                }
+                res.push(fg);
+                fg = Default::default();
+                total_ng = 0;
+                max_party = 0;
+                min_party = 0;
+            }
+        }
+        for id in rm_ids {
+            self.ready_groups.remove(&id);
+        }
+        res
+    }
+}
+
+impl Matchmaker for GreedyMatchmaker {
+    fn find_matches(&mut self, rooms: &BTreeMap<u32, Rc<RefCell<QueueRoomData>>>) -> Vec<ReadyGameData> {
+        if rooms.len() >= self.mode.match_size {
+            self.make_groups(rooms);
+        }
+        if self.ready_groups.len() >= self.mode.match_size {
+            return self.make_games();
+        }
+        vec![]
+    }
+
+    fn remove_room(&mut self, rid: u32, rooms: &BTreeMap<u32, Rc<RefCell<QueueRoomData>>>) {
+        let r = rooms.get(&rid);
+        if let Some(r) = r {
+            let gid = r.borrow().gid;
+            let rg = self.ready_groups.get(&gid);
+            if let Some(rg) = rg {
+                for id in &rg.borrow().rid {
+                    if *id == rid {
+                        continue;
+                    }
+                    let room = rooms.get(id);
+                    if let Some(room) = room {
+                        room.borrow_mut().gid = 0;
+                        room.borrow_mut().ready = 0;
+                    }
+                }
+            }
+            self.ready_groups.remove(&gid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(rid: u32, ng: i16, user_len: i16) -> Rc<RefCell<QueueRoomData>> {
+        Rc::new(RefCell::new(QueueRoomData {
+            rid: rid,
+            user_len: user_len,
+            avg_ng: ng,
+            queue_cnt: 1,
+            users: (0..user_len).map(|i| format!("{}-{}", rid, i)).collect(),
+            roles: vec![vec![]; user_len as usize],
+            ..Default::default()
+        }))
+    }
+
+    fn rooms(list: Vec<Rc<RefCell<QueueRoomData>>>) -> BTreeMap<u32, Rc<RefCell<QueueRoomData>>> {
+        list.into_iter().map(|r| (r.borrow().rid, Rc::clone(&r))).collect()
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let mut mm = GreedyMatchmaker::new(ModeConfig::default());
+        let rooms = rooms(vec![room(1, 1000, 1), room(2, 1050, 1), room(3, 1500, 1)]);
+        let games = mm.find_matches(&rooms);
+        println!("{:?}", games);
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].group, vec![vec![1], vec![2]]);
+        // the far away room keeps waiting in its own group
+        assert_eq!(mm.ready_groups.len(), 1);
+        assert_eq!(mm.find_matches(&rooms).len(), 0);
+    }
+
+    #[test]
+    fn test_remove_room() {
+        let mut mode = ModeConfig::default();
+        mode.team_size = 2;
+        let mut mm = GreedyMatchmaker::new(mode);
+        let rooms = rooms(vec![room(1, 1000, 1), room(2, 1010, 1), room(3, 2000, 1)]);
+        assert_eq!(mm.find_matches(&rooms).len(), 0);
+        assert_eq!(mm.ready_groups.len(), 1);
+        assert_eq!(rooms[&2].borrow().ready, 1);
+        mm.remove_room(1, &rooms);
+        assert_eq!(mm.ready_groups.len(), 0);
+        assert_eq!(rooms[&2].borrow().ready, 0);
+        assert_eq!(rooms[&2].borrow().gid, 0);
+    }
+
+    #[test]
+    fn test_premade_bonus() {
+        let mut mode = ModeConfig::default();
+        mode.premade_bonus = 50;
+        let mm = GreedyMatchmaker::new(mode);
+        assert_eq!(mm.room_ng(&room(1, 1000, 1).borrow()), 1000);
+        assert_eq!(mm.room_ng(&room(2, 1000, 3).borrow()), 1100);
+    }
+
+    #[test]
+    fn test_party_size_penalty() {
+        // a party of two against two solo players with the same ng
+        let mut mode = ModeConfig::default();
+        mode.team_size = 2;
+        let mut mm = GreedyMatchmaker::new(mode.clone());
+        let list = || rooms(vec![room(1, 1000, 2), room(2, 1000, 1), room(3, 1000, 1)]);
+        assert_eq!(mm.find_matches(&list()).len(), 1);
+
+        mode.party_size_penalty = 150;
+        let mut mm = GreedyMatchmaker::new(mode);
+        assert_eq!(mm.find_matches(&list()).len(), 0);
+        assert_eq!(mm.ready_groups.len(), 2);
+    }
+
+    #[test]
+    fn test_party_mix_ok() {
+        let mut mode = ModeConfig::default();
+        let mm = GreedyMatchmaker::new(mode.clone());
+        assert!(mm.party_mix_ok(5, 5, 1, 1));
+        mode.solo_party_limit = 3;
+        let mm = GreedyMatchmaker::new(mode);
+        assert!(!mm.party_mix_ok(3, 3, 1, 1));
+        assert!(!mm.party_mix_ok(1, 1, 4, 2));
+        assert!(mm.party_mix_ok(2, 1, 1, 1));
+        assert!(mm.party_mix_ok(3, 3, 2, 2));
+        // an empty group takes anyone
+        assert!(mm.party_mix_ok(0, 0, 1, 1));
+    }
+
+    #[test]
+    fn test_assign_roles() {
+        let s = |v: &[&str]| v.iter().map(|r| r.to_string()).collect::<Vec<String>>();
+        let template = s(&["top", "mid", "support"]);
+        // the second player only plays mid, so the first one moves to support
+        let prefs = vec![s(&["mid", "support"]), s(&["mid"]), s(&[])];
+        let res = assign_roles(&prefs, &template).unwrap();
+        println!("{:?}", res);
+        assert_eq!(res, vec![2, 1, 0]);
+        assert_eq!(assign_roles(&vec![s(&["mid"]), s(&["mid"])], &template), None);
+        assert_eq!(assign_roles(&vec![s(&["jungle"])], &template), None);
+        assert_eq!(assign_roles(&vec![vec![]; 4], &template), None);
+        assert_eq!(assign_roles(&vec![], &template), Some(vec![]));
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+const MAX_BUCKETS: usize = 10;
+
+// bounds are upper limits in seconds, at most MAX_BUCKETS of them
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: [AtomicU64; MAX_BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [f64]) -> Histogram {
+        // written out, repeating a const AtomicU64 needs a newer compiler
+        let buckets = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+            AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+        Histogram { bounds: bounds, buckets: buckets, count: AtomicU64::new(0), sum_micros: AtomicU64::new(0) }
+    }
+
+    pub fn observe(&self, secs: f64) {
+        for (i, b) in self.bounds.iter().take(MAX_BUCKETS).enumerate() {
+            if secs <= *b {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add((secs * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut total = 0;
+        for (i, b) in self.bounds.iter().take(MAX_BUCKETS).enumerate() {
+            total += self.buckets[i].load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, b, total).ok();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).ok();
+        writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6).ok();
+        writeln!(out, "{}_count {}", name, count).ok();
+    }
+}
+
+pub static MATCHES_TOTAL: Counter = Counter::new();
+pub static PRESTART_ACCEPT: Counter = Counter::new();
+pub static PRESTART_DECLINE: Counter = Counter::new();
+pub static MQTT_PUBLISH_FAILURES: Counter = Counter::new();
+pub static ACTIVE_GAMES: Gauge = Gauge::new();
+pub static ROOM_CHANNEL: Gauge = Gauge::new();
+pub static QUEUE_CHANNEL: Gauge = Gauge::new();
+pub static SQL_CHANNEL: Gauge = Gauge::new();
+pub static MQTT_CHANNEL: Gauge = Gauge::new();
+pub static QUEUE_WAIT: Histogram = Histogram::new(&[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]);
+pub static SQL_BATCH: Histogram = Histogram::new(&[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]);
+lazy_static! {
+    // rooms waiting per mode, modes seen once stay at 0
+    static ref QUEUE_ROOMS: Mutex<BTreeMap<String, i64>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn set_queue_rooms(counts: &BTreeMap<String, usize>) {
+    if let Ok(mut q) = QUEUE_ROOMS.lock() {
+        for v in q.values_mut() {
+            *v = 0;
+        }
+        for (mode, n) in counts {
+            q.insert(mode.clone(), *n as i64);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
+
+// prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    header(&mut out, "erps_queue_rooms", "gauge", "Rooms waiting in the queue.");
+    if let Ok(q) = QUEUE_ROOMS.lock() {
+        for (mode, n) in q.iter() {
+            writeln!(out, "erps_queue_rooms{{mode=\"{}\"}} {}", mode, n).ok();
+        }
+    }
+    header(&mut out, "erps_queue_wait_seconds", "histogram", "Time a room spent in the queue before a match was found.");
+    QUEUE_WAIT.render(&mut out, "erps_queue_wait_seconds");
+    header(&mut out, "erps_matches_total", "counter", "Matches formed by the matchmakers.");
+    writeln!(out, "erps_matches_total {}", MATCHES_TOTAL.get()).ok();
+    header(&mut out, "erps_prestart_total", "counter", "Prestart answers by result.");
+    writeln!(out, "erps_prestart_total{{result=\"accept\"}} {}", PRESTART_ACCEPT.get()).ok();
+    writeln!(out, "erps_prestart_total{{result=\"decline\"}} {}", PRESTART_DECLINE.get()).ok();
+    header(&mut out, "erps_active_games", "gauge", "Games being played.");
+    writeln!(out, "erps_active_games {}", ACTIVE_GAMES.get()).ok();
+    header(&mut out, "erps_channel_depth", "gauge", "Messages waiting in the internal channels.");
+    for (name, g) in &[("room", &ROOM_CHANNEL), ("queue", &QUEUE_CHANNEL), ("sql", &SQL_CHANNEL), ("mqtt", &MQTT_CHANNEL)] {
+        writeln!(out, "erps_channel_depth{{channel=\"{}\"}} {}", name, g.get()).ok();
+    }
+    header(&mut out, "erps_sql_batch_seconds", "histogram", "Time to write one batch of queued sql.");
+    SQL_BATCH.render(&mut out, "erps_sql_batch_seconds");
+    header(&mut out, "erps_mqtt_publish_failures_total", "counter", "Failed mqtt publishes.");
+    writeln!(out, "erps_mqtt_publish_failures_total {}", MQTT_PUBLISH_FAILURES.get()).ok();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        static H: Histogram = Histogram::new(&[1.0, 10.0]);
+        H.observe(0.5);
+        H.observe(5.0);
+        H.observe(50.0);
+        let mut out = String::new();
+        H.render(&mut out, "h");
+        println!("{}", out);
+        assert!(out.contains("h_bucket{le=\"1\"} 1\n"));
+        assert!(out.contains("h_bucket{le=\"10\"} 2\n"));
+        assert!(out.contains("h_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_sum 55.5\n"));
+
+        let mut counts = BTreeMap::new();
+        counts.insert("ng".to_string(), 3);
+        set_queue_rooms(&counts);
+        set_queue_rooms(&BTreeMap::new());
+        MATCHES_TOTAL.inc();
+        let out = render();
+        assert!(out.contains("erps_queue_rooms{mode=\"ng\"} 0\n"));
+        assert!(out.contains("erps_matches_total 1\n"));
+    }
+}
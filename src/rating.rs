@@ -0,0 +1,319 @@
+use failure::Error;
+use crate::config::{ModeConfig, KSchedule};
+use crate::elo::*;
+use crate::glicko2::*;
+use crate::trueskill::*;
+
+// score is the ng scale rating, rd and vol are only used by backends that track uncertainty
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct PlayerRating {
+    pub score: f32,
+    pub rd: f32,
+    pub vol: f32,
+    pub games: u32,
+    // positive for a win streak, negative for a loss streak
+    pub streak: i32,
+}
+
+// rd and vol belong to one backend, a trueskill sigma means nothing to glicko2
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Deviation {
+    pub rd: f32,
+    pub vol: f32,
+}
+
+// name the deviations of a mode's backend are stored under, Config::check only lets known backends through
+pub fn backend_name(mode: &ModeConfig) -> &str {
+    mode.rating.as_str()
+}
+
+pub fn initial_deviation(backend: &str) -> Deviation {
+    match backend {
+        "trueskill" => Deviation {rd: DEFAULT_SIGMA, vol: DEFAULT_VOL},
+        _ => Deviation {rd: DEFAULT_RD, vol: DEFAULT_VOL},
+    }
+}
+
+// counts a finished game, result is 1 for a win, -1 for a loss and 0 for a draw
+pub fn count_game(r: &PlayerRating, result: i32) -> PlayerRating {
+    let streak = if result == 0 {
+        0
+    } else if r.streak * result > 0 {
+        r.streak + result
+    } else {
+        result
+    };
+    PlayerRating {games: r.games + 1, streak: streak, ..*r}
+}
+
+// visible rank after a game with the new games count and the ng before and after it
+pub fn next_rank(mode: &ModeConfig, games: u32, old_ng: i16, ng: i16, rk: i16) -> i16 {
+    if games < mode.placement_games {
+        return rk;
+    }
+    if games == mode.placement_games {
+        return ng - mode.placement_rank_offset;
+    }
+    let rk = rk + ng - old_ng;
+    rk + ((ng - rk) as f32 * mode.rank_pull).round() as i16
+}
+
+pub trait RatingSystem {
+    fn initial(&self) -> PlayerRating;
+
+    /// Chance that team a beats team b.
+    fn expected(&self, a: &Vec<PlayerRating>, b: &Vec<PlayerRating>) -> f32;
+
+    /// Two team result, with draw set neither team won.
+    fn update_teams(&self, win: &Vec<PlayerRating>, lose: &Vec<PlayerRating>, draw: bool)
+        -> (Vec<PlayerRating>, Vec<PlayerRating>);
+
+    /// Teams ordered from first to last place.
+    fn update_placement(&self, teams: &Vec<Vec<PlayerRating>>) -> Vec<Vec<PlayerRating>>;
+}
+
+// Settles a reported game. Teams are win then lose for a two team result, or first to last
+// place for a placement game where first counts as a win and last as a loss for the streak.
+// Returns every team's new ratings with the result they were counted with.
+pub fn settle(rating: &dyn RatingSystem, teams: &Vec<Vec<PlayerRating>>, placement: bool, draw: bool) -> Vec<(Vec<PlayerRating>, i32)> {
+    let (res, results) = if placement {
+        let n = teams.len();
+        (rating.update_placement(teams), (0..n).map(|i| if i == 0 { 1 } else if i == n - 1 { -1 } else { 0 }).collect())
+    } else {
+        let (w, l) = rating.update_teams(&teams[0], &teams[1], draw);
+        let r = if draw { 0 } else { 1 };
+        (vec![w, l], vec![r, -r])
+    };
+    res.iter().zip(results.iter()).map(|(t, r)| (t.iter().map(|p| count_game(p, *r)).collect(), *r)).collect()
+}
+
+pub fn create_rating(mode: &ModeConfig) -> Result<Box<dyn RatingSystem>, Error> {
+    match mode.rating.as_str() {
+        "elo" => Ok(Box::new(EloPlacement {elo: EloRank {k: mode.elo_k}, schedule: mode.k_schedule.clone()})),
+        "glicko2" => Ok(Box::new(Glicko2 {tau: mode.glicko_tau})),
+        "trueskill" => Ok(Box::new(TrueSkill {beta: mode.trueskill_beta, tau: mode.trueskill_tau, draw_probability: mode.draw_probability})),
+        name => Err(failure::err_msg(format!("unknown rating {}", name))),
+    }
+}
+
+fn team_mean(team: &Vec<PlayerRating>) -> f32 {
+    team.iter().map(|r| r.score).sum::<f32>() / team.len() as f32
+}
+
+pub struct EloPlacement {
+    pub elo: EloRank,
+    pub schedule: KSchedule,
+}
+
+impl EloPlacement {
+    pub fn k_factor(&self, r: &PlayerRating) -> f32 {
+        self.schedule.k(self.elo.k, r.games, r.score, r.streak)
+    }
+
+    fn update_one(&self, r: &PlayerRating, opponent: f32, actual: f32) -> PlayerRating {
+        let elo = EloRank {k: self.k_factor(r)};
+        let expected = elo.get_expected(r.score, opponent);
+        PlayerRating {score: elo.rating(expected, actual, r.score), ..*r}
+    }
+}
+
+impl RatingSystem for EloPlacement {
+    fn initial(&self) -> PlayerRating {
+        PlayerRating {score: 1000.0, rd: DEFAULT_RD, vol: DEFAULT_VOL, ..Default::default()}
+    }
+
+    fn expected(&self, a: &Vec<PlayerRating>, b: &Vec<PlayerRating>) -> f32 {
+        self.elo.get_expected(team_mean(a), team_mean(b))
+    }
+
+    fn update_teams(&self, win: &Vec<PlayerRating>, lose: &Vec<PlayerRating>, draw: bool)
+        -> (Vec<PlayerRating>, Vec<PlayerRating>) {
+        let w = team_mean(win);
+        let l = team_mean(lose);
+        let s = if draw { 0.5 } else { 1.0 };
+        let wint = win.iter().map(|r| self.update_one(r, l, s)).collect();
+        let loset = lose.iter().map(|r| self.update_one(r, w, 1.0 - s)).collect();
+        (wint, loset)
+    }
+
+    // every team is rated by its mean against the rest of the field, first place scores 1 and
+    // last place 0, the expected score is the average chance to beat each of the other teams
+    fn update_placement(&self, teams: &Vec<Vec<PlayerRating>>) -> Vec<Vec<PlayerRating>> {
+        let n = teams.len();
+        if n < 2 {
+            return teams.clone();
+        }
+        let means: Vec<f32> = teams.iter().map(team_mean).collect();
+        teams.iter().enumerate().map(|(i, t)| {
+            let actual = (n - 1 - i) as f32 / (n - 1) as f32;
+            let expected = means.iter().enumerate().filter(|(j, _)| *j != i)
+                .map(|(_, m)| self.elo.get_expected(means[i], *m)).sum::<f32>() / (n - 1) as f32;
+            t.iter().map(|r| PlayerRating {score: (r.score + self.k_factor(r) * (actual - expected)).round(), ..*r}).collect()
+        }).collect()
+    }
+}
+
+fn to_glicko(r: &PlayerRating) -> GlickoRating {
+    GlickoRating {rating: r.score, rd: r.rd, vol: r.vol}
+}
+
+fn from_glicko(r: &GlickoRating, old: &PlayerRating) -> PlayerRating {
+    PlayerRating {score: r.rating, rd: r.rd, vol: r.vol, ..*old}
+}
+
+impl RatingSystem for Glicko2 {
+    fn initial(&self) -> PlayerRating {
+        from_glicko(&GlickoRating::default(), &Default::default())
+    }
+
+    fn expected(&self, a: &Vec<PlayerRating>, b: &Vec<PlayerRating>) -> f32 {
+        let a = self.composite(&a.iter().map(to_glicko).collect());
+        let b = self.composite(&b.iter().map(to_glicko).collect());
+        self.get_expected(&a, &b)
+    }
+
+    fn update_teams(&self, win: &Vec<PlayerRating>, lose: &Vec<PlayerRating>, draw: bool)
+        -> (Vec<PlayerRating>, Vec<PlayerRating>) {
+        let s = if draw { 0.5 } else { 1.0 };
+        let w = self.composite(&win.iter().map(to_glicko).collect());
+        let l = self.composite(&lose.iter().map(to_glicko).collect());
+        let wint = win.iter().map(|r| from_glicko(&self.update(&to_glicko(r), &vec![(l, s)]), r)).collect();
+        let loset = lose.iter().map(|r| from_glicko(&self.update(&to_glicko(r), &vec![(w, 1.0 - s)]), r)).collect();
+        (wint, loset)
+    }
+
+    // one rating period with a result against every other team
+    fn update_placement(&self, teams: &Vec<Vec<PlayerRating>>) -> Vec<Vec<PlayerRating>> {
+        let comps: Vec<GlickoRating> = teams.iter().map(|t| self.composite(&t.iter().map(to_glicko).collect())).collect();
+        teams.iter().enumerate().map(|(i, t)| {
+            let mut results = vec![];
+            for (j, c) in comps.iter().enumerate() {
+                if i != j {
+                    results.push((*c, if i < j { 1.0 } else { 0.0 }));
+                }
+            }
+            t.iter().map(|r| from_glicko(&self.update(&to_glicko(r), &results), r)).collect()
+        }).collect()
+    }
+}
+
+fn to_skill(r: &PlayerRating) -> SkillRating {
+    SkillRating {mu: r.score, sigma: r.rd}
+}
+
+fn from_skill(r: &SkillRating, old: &PlayerRating) -> PlayerRating {
+    PlayerRating {score: r.mu, rd: r.sigma, ..*old}
+}
+
+impl RatingSystem for TrueSkill {
+    fn initial(&self) -> PlayerRating {
+        PlayerRating {score: DEFAULT_MU, rd: DEFAULT_SIGMA, vol: DEFAULT_VOL, ..Default::default()}
+    }
+
+    fn expected(&self, a: &Vec<PlayerRating>, b: &Vec<PlayerRating>) -> f32 {
+        self.get_expected(&a.iter().map(to_skill).collect(), &b.iter().map(to_skill).collect())
+    }
+
+    fn update_teams(&self, win: &Vec<PlayerRating>, lose: &Vec<PlayerRating>, draw: bool)
+        -> (Vec<PlayerRating>, Vec<PlayerRating>) {
+        let (rw, rl) = self.compute_team(&win.iter().map(to_skill).collect(), &lose.iter().map(to_skill).collect(), draw);
+        let wint = win.iter().zip(rw.iter()).map(|(o, r)| from_skill(r, o)).collect();
+        let loset = lose.iter().zip(rl.iter()).map(|(o, r)| from_skill(r, o)).collect();
+        (wint, loset)
+    }
+
+    // approximated as a chain of two team games between neighbouring places
+    fn update_placement(&self, teams: &Vec<Vec<PlayerRating>>) -> Vec<Vec<PlayerRating>> {
+        let mut res = teams.clone();
+        for i in 1..teams.len() {
+            let (a, b) = self.update_teams(&res[i-1], &res[i], false);
+            res[i-1] = a;
+            res[i] = b;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends() -> Vec<Box<dyn RatingSystem>> {
+        ["elo", "glicko2", "trueskill"].iter().map(|name| {
+            let mut mode = ModeConfig::default();
+            mode.rating = name.to_string();
+            create_rating(&mode).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn test_count_game() {
+        let r = PlayerRating::default();
+        let r = count_game(&r, 1);
+        assert_eq!((r.games, r.streak), (1, 1));
+        let r = count_game(&r, 1);
+        assert_eq!((r.games, r.streak), (2, 2));
+        let r = count_game(&r, -1);
+        assert_eq!((r.games, r.streak), (3, -1));
+        let r = count_game(&r, -1);
+        assert_eq!((r.games, r.streak), (4, -2));
+        let r = count_game(&r, 0);
+        assert_eq!((r.games, r.streak), (5, 0));
+    }
+
+    #[test]
+    fn test_settle() {
+        let elo = create_rating(&ModeConfig::default()).unwrap();
+        let init = elo.initial();
+        let res = settle(&*elo, &vec![vec![init; 2], vec![init; 2], vec![init; 2]], true, false);
+        println!("{:?}", res);
+        assert_eq!(res.iter().map(|(_, r)| *r).collect::<Vec<i32>>(), vec![1, 0, -1]);
+        assert!(res.iter().all(|(t, _)| t.iter().all(|p| p.games == 1)));
+        assert_eq!(res[0].0[0].streak, 1);
+        assert_eq!(res[2].0[1].streak, -1);
+        assert!(res[0].0[0].score > res[2].0[0].score);
+
+        let res = settle(&*elo, &vec![vec![init], vec![init]], false, true);
+        assert_eq!(res.iter().map(|(_, r)| *r).collect::<Vec<i32>>(), vec![0, 0]);
+        assert_eq!(res[0].0[0].score, init.score);
+        let res = settle(&*elo, &vec![vec![init], vec![init]], false, false);
+        assert_eq!((res[0].0[0].streak, res[1].0[0].streak), (1, -1));
+    }
+
+    #[test]
+    fn test_update_teams() {
+        for rating in backends() {
+            let init = rating.initial();
+            let team = vec![init; 3];
+            let (w, l) = rating.update_teams(&team, &team, false);
+            println!("win {:?}\nlose {:?}", w[0], l[0]);
+            assert!(w.iter().all(|r| r.score > init.score));
+            assert!(l.iter().all(|r| r.score < init.score));
+            let (w, l) = rating.update_teams(&team, &team, true);
+            assert!((w[0].score - init.score).abs() < 1.0);
+            assert!((l[0].score - init.score).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_update_placement() {
+        for rating in backends() {
+            let init = rating.initial();
+            let teams = vec![vec![init; 2]; 4];
+            let res = rating.update_placement(&teams);
+            let scores: Vec<f32> = res.iter().map(|t| t[0].score).collect();
+            println!("placement {:?}", scores);
+            assert!(scores[0] > init.score);
+            assert!(scores[3] < init.score);
+            assert!(scores[0] > scores[3]);
+        }
+        // elo moves every place by the same step and never hands out more than k
+        let elo = create_rating(&ModeConfig::default()).unwrap();
+        let init = elo.initial();
+        let res = elo.update_placement(&vec![vec![init]; 3]);
+        let scores: Vec<f32> = res.iter().map(|t| t[0].score).collect();
+        assert_eq!(scores, vec![1010.0, 1000.0, 990.0]);
+        let res = elo.update_placement(&vec![vec![init]; 8]);
+        assert!(res.iter().all(|t| (t[0].score - init.score).abs() <= 10.0));
+    }
+}
@@ -1,7 +1,10 @@
 use serde_derive::{Serialize, Deserialize};
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::Rc;
 use crate::msg::*;
+use crate::tier::TierState;
+use crate::rating::{Deviation, initial_deviation};
 use crossbeam_channel::{bounded, tick, Sender, Receiver, select};
 use failure::Error;
 
@@ -12,12 +15,32 @@ pub struct User {
     pub hero: String,
     pub ng: i16,
     pub rk: i16,
+    // per rating backend, see backend_name
+    pub deviations: BTreeMap<String, Deviation>,
+    pub games: u32,
+    pub streak: i32,
+    // unix seconds of the last settled game and the last decay step
+    pub last_game: u64,
+    pub last_decay: u64,
+    // rk lost to decay since the last login
+    pub decay_pending: i16,
+    pub region: String,
     pub rid: u32,
     pub gid: u32,
     pub game_id: u32,
     pub online: bool,
     pub start_prestart: bool,
     pub prestart_get: bool,
+    pub roles: Vec<String>,
+    pub role: String,
+    pub tier: TierState,
+}
+
+impl User {
+    // backends the user never played start from their initial deviation
+    pub fn deviation(&self, backend: &str) -> Deviation {
+        self.deviations.get(backend).cloned().unwrap_or_else(|| initial_deviation(backend))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -30,6 +53,7 @@ pub struct RoomData {
     pub avg_rk: i16,
     pub ready: i8,
     pub queue_cnt: i16,
+    pub mode: String,
 }
 
 impl RoomData {
@@ -46,6 +70,48 @@ impl RoomData {
         }
     }
 
+    // effective rating of the party, the mean pulled toward the best member
+    pub fn party_ng(&self, top_weight: f32) -> i16 {
+        let mut top = 0;
+        for user in &self.users {
+            if user.borrow().ng > top {
+                top = user.borrow().ng;
+            }
+        }
+        let avg = self.avg_ng as f32;
+        (avg + (top as f32 - avg) * top_weight).round() as i16
+    }
+
+    // ng spread between the best and worst member if a player with `ng` joined
+    pub fn ng_spread_with(&self, ng: i16) -> i16 {
+        let mut max = ng;
+        let mut min = ng;
+        for user in &self.users {
+            let v = user.borrow().ng;
+            if v > max {
+                max = v;
+            }
+            if v < min {
+                min = v;
+            }
+        }
+        max - min
+    }
+
+    pub fn avg_rd(&self, backend: &str) -> f32 {
+        if self.users.len() == 0 {
+            return 0.0;
+        }
+        self.users.iter().map(|u| u.borrow().deviation(backend).rd).sum::<f32>() / self.users.len() as f32
+    }
+
+    pub fn ng_spread(&self) -> i16 {
+        match self.users.first() {
+            Some(u) => self.ng_spread_with(u.borrow().ng),
+            None => 0,
+        }
+    }
+
     pub fn add_user(&mut self, user: Rc<RefCell<User>>) {
         user.borrow_mut().rid = self.rid;
         self.users.push(Rc::clone(&user));
@@ -156,11 +222,11 @@ impl FightGroup {
         false
     }
 
-    pub fn get_users_id_hero(&self) -> Vec<(String, String, String)> {
-        let mut res: Vec<(String, String, String)> = vec![];
+    pub fn get_users_id_hero(&self) -> Vec<(String, String, String, String)> {
+        let mut res: Vec<(String, String, String, String)> = vec![];
         for r in &self.rooms {
             for u in &r.borrow().users {
-                res.push((u.borrow().id.clone(), u.borrow().name.clone(), u.borrow().hero.clone()));
+                res.push((u.borrow().id.clone(), u.borrow().name.clone(), u.borrow().hero.clone(), u.borrow().role.clone()));
             }
         }
         res
@@ -286,6 +352,11 @@ pub struct FightGame {
     pub winteam: i16,
     pub game_status: u16,
     pub game_port: u16,
+    pub mode: String,
+    // handed to the game server on launch, every report from it must carry it
+    pub secret: String,
+    // players whose game_info has been recorded
+    pub info_ids: Vec<String>,
 }
 
 #[derive(PartialEq)]
@@ -0,0 +1,206 @@
+use serde_derive::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Tier {
+    pub name: String,
+    // lowest rk of the tier, the tier ends where the next one starts
+    pub min_rk: i16,
+    pub divisions: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TierConfig {
+    // sorted by min_rk
+    pub tiers: Vec<Tier>,
+    // best of this many games to enter a new tier, 0 promotes straight away
+    pub series_games: u16,
+    // rk can drop this far below a division before the player is demoted
+    pub demotion_buffer: i16,
+}
+
+fn tier(name: &str, min_rk: i16, divisions: u16) -> Tier {
+    Tier { name: name.to_string(), min_rk: min_rk, divisions: divisions }
+}
+
+impl Default for TierConfig {
+    fn default() -> TierConfig {
+        TierConfig {
+            tiers: vec![
+                tier("Bronze", 0, 3),
+                tier("Silver", 900, 3),
+                tier("Gold", 1000, 3),
+                tier("Platinum", 1100, 3),
+                tier("Diamond", 1200, 3),
+                tier("Master", 1400, 1),
+            ],
+            series_games: 3,
+            demotion_buffer: 20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TierState {
+    // index into the flattened list of tier divisions, 0 is the lowest
+    pub level: u16,
+    pub in_series: bool,
+    pub series_wins: u16,
+    pub series_losses: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TierChange {
+    None,
+    Placed,
+    Promote,
+    Demote,
+    SeriesStart,
+    SeriesUpdate,
+    SeriesFail,
+}
+
+impl TierConfig {
+    // (tier index, division index, min rk) of every division from the lowest up
+    fn levels(&self) -> Vec<(usize, u16, i16)> {
+        let mut res = vec![];
+        for (i, t) in self.tiers.iter().enumerate() {
+            let end = match self.tiers.get(i+1) {
+                Some(n) => n.min_rk,
+                None => t.min_rk,
+            };
+            let divisions = if t.divisions == 0 { 1 } else { t.divisions };
+            let step = (end - t.min_rk) / divisions as i16;
+            for d in 0..divisions {
+                res.push((i, d, t.min_rk + step * d as i16));
+            }
+        }
+        res
+    }
+
+    pub fn level_of(&self, rk: i16) -> u16 {
+        let mut level = 0;
+        for (i, (_, _, min)) in self.levels().iter().enumerate() {
+            if rk >= *min {
+                level = i as u16;
+            }
+        }
+        level
+    }
+
+    // "Gold 2", divisions count down toward the next tier
+    pub fn name(&self, level: u16) -> String {
+        let levels = self.levels();
+        match levels.get(level as usize) {
+            Some((t, d, _)) => {
+                let t = &self.tiers[*t];
+                if t.divisions > 1 {
+                    format!("{} {}", t.name, t.divisions - d)
+                } else {
+                    t.name.clone()
+                }
+            },
+            None => "".to_string(),
+        }
+    }
+
+    // tier name without the division, used to bracket statistics
+    pub fn tier_name(&self, rk: i16) -> String {
+        match self.levels().get(self.level_of(rk) as usize) {
+            Some((t, _, _)) => self.tiers[*t].name.clone(),
+            None => "".to_string(),
+        }
+    }
+
+    // first tier after placement
+    pub fn place(&self, rk: i16) -> TierState {
+        TierState { level: self.level_of(rk), ..Default::default() }
+    }
+
+    // result is 1 for a win, -1 for a loss and 0 for a draw, rk is the rank after the game
+    pub fn update(&self, state: &TierState, rk: i16, result: i32) -> (TierState, TierChange) {
+        let levels = self.levels();
+        let top = levels.len() as u16 - 1;
+        let mut s = state.clone();
+        if s.level > top {
+            s.level = top;
+        }
+        let need = self.series_games / 2 + 1;
+        if s.in_series {
+            // draws and rank changes outside of games leave a series alone
+            if result == 0 {
+                return (s, TierChange::None);
+            }
+            if result > 0 {
+                s.series_wins += 1;
+            } else if result < 0 {
+                s.series_losses += 1;
+            }
+            if s.series_wins >= need {
+                return (TierState { level: s.level + 1, ..Default::default() }, TierChange::Promote);
+            }
+            if s.series_losses >= need {
+                return (TierState { level: s.level, ..Default::default() }, TierChange::SeriesFail);
+            }
+            return (s, TierChange::SeriesUpdate);
+        }
+        let target = self.level_of(rk);
+        if target > s.level {
+            let next = s.level as usize + 1;
+            // a series guards every tier boundary, divisions inside a tier are free
+            if self.series_games > 0 && levels[next].0 != levels[s.level as usize].0 {
+                s.in_series = true;
+                s.series_wins = 0;
+                s.series_losses = 0;
+                return (s, TierChange::SeriesStart);
+            }
+            s.level += 1;
+            return (s, TierChange::Promote);
+        }
+        if s.level > 0 && rk < levels[s.level as usize].2 - self.demotion_buffer {
+            s.level -= 1;
+            return (s, TierChange::Demote);
+        }
+        (s, TierChange::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tier() {
+        let t = TierConfig::default();
+        let s = t.place(1000);
+        println!("{:?} {}", s, t.name(s.level));
+        assert_eq!(t.name(s.level), "Gold 3");
+        assert_eq!(t.tier_name(1050), "Gold");
+
+        // inside a tier divisions move without a series
+        let (s, c) = t.update(&s, 1040, 1);
+        assert_eq!(c, TierChange::Promote);
+        assert_eq!(t.name(s.level), "Gold 2");
+
+        // demotion waits for the buffer
+        let (s, c) = t.update(&s, 1025, -1);
+        assert_eq!(c, TierChange::None);
+        let (s, c) = t.update(&s, 1010, -1);
+        assert_eq!(c, TierChange::Demote);
+        assert_eq!(t.name(s.level), "Gold 3");
+
+        // into platinum through a best of three
+        let s = t.place(1090);
+        let (s, c) = t.update(&s, 1110, 1);
+        assert_eq!(c, TierChange::SeriesStart);
+        let (s, c) = t.update(&s, 1100, 0);
+        assert_eq!(c, TierChange::None);
+        let (s, _) = t.update(&s, 1120, 1);
+        let (s, c) = t.update(&s, 1100, -1);
+        assert_eq!(c, TierChange::SeriesUpdate);
+        let (s, c) = t.update(&s, 1120, 1);
+        assert_eq!(c, TierChange::Promote);
+        println!("{:?} {}", s, t.name(s.level));
+        assert_eq!(t.name(s.level), "Platinum 3");
+    }
+}
@@ -0,0 +1,173 @@
+use std::f64::consts::{PI, SQRT_2};
+
+// TrueSkill defaults (mu 25, sigma 25/3) scaled by 40 onto the ng scale
+pub const DEFAULT_MU: f32 = 1000.0;
+pub const DEFAULT_SIGMA: f32 = 1000.0 / 3.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SkillRating {
+    pub mu: f32,
+    pub sigma: f32,
+}
+
+impl Default for SkillRating {
+    fn default() -> SkillRating {
+        SkillRating { mu: DEFAULT_MU, sigma: DEFAULT_SIGMA }
+    }
+}
+
+// Two team TrueSkill: a team performs as the sum of its players' skills and
+// every player moves in proportion to its own variance.
+pub struct TrueSkill {
+    pub beta: f32,
+    pub tau: f32,
+    pub draw_probability: f32,
+}
+
+impl Default for TrueSkill {
+    fn default() -> TrueSkill {
+        TrueSkill {
+            beta: DEFAULT_SIGMA / 2.0,
+            tau: DEFAULT_SIGMA / 100.0,
+            draw_probability: 0.1,
+        }
+    }
+}
+
+fn pdf(x: f64) -> f64 {
+    (-x * x / 2.0).exp() / (2.0 * PI).sqrt()
+}
+
+// complementary error function, Numerical Recipes erfcc
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t * (-z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418 +
+        t * (-0.18628806 + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 +
+        t * (-0.82215223 + t * 0.17087277))))))))).exp();
+    if x >= 0.0 { r } else { 2.0 - r }
+}
+
+fn cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / SQRT_2)
+}
+
+fn inv_cdf(p: f64) -> f64 {
+    let mut lo = -10.0;
+    let mut hi = 10.0;
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        if cdf(mid) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+fn v_win(t: f64, e: f64) -> f64 {
+    let denom = cdf(t - e);
+    if denom < 2.222758749e-162 {
+        return -t + e;
+    }
+    pdf(t - e) / denom
+}
+
+fn w_win(t: f64, e: f64) -> f64 {
+    let v = v_win(t, e);
+    v * (v + t - e)
+}
+
+fn v_draw(t: f64, e: f64) -> f64 {
+    let t = t.abs();
+    let denom = cdf(e - t) - cdf(-e - t);
+    if denom < 2.222758749e-162 {
+        return -t - e;
+    }
+    (pdf(-e - t) - pdf(e - t)) / denom
+}
+
+fn w_draw(t: f64, e: f64) -> f64 {
+    let t = t.abs();
+    let denom = cdf(e - t) - cdf(-e - t);
+    if denom < 2.222758749e-162 {
+        return 1.0;
+    }
+    let v = v_draw(t, e);
+    v * v + ((e - t) * pdf(e - t) + (e + t) * pdf(e + t)) / denom
+}
+
+impl TrueSkill {
+    pub fn draw_margin(&self, players: usize) -> f64 {
+        inv_cdf((self.draw_probability as f64 + 1.0) / 2.0) * (players as f64).sqrt() * self.beta as f64
+    }
+
+    fn c(&self, a: &Vec<SkillRating>, b: &Vec<SkillRating>) -> f64 {
+        let beta = self.beta as f64;
+        let tau = self.tau as f64;
+        a.iter().chain(b.iter())
+            .map(|r| beta * beta + r.sigma as f64 * r.sigma as f64 + tau * tau)
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    // chance that team a beats team b
+    pub fn get_expected(&self, a: &Vec<SkillRating>, b: &Vec<SkillRating>) -> f32 {
+        let mu_a: f64 = a.iter().map(|r| r.mu as f64).sum();
+        let mu_b: f64 = b.iter().map(|r| r.mu as f64).sum();
+        cdf((mu_a - mu_b) / self.c(a, b)) as f32
+    }
+
+    pub fn compute_team(&self, winteam: &Vec<SkillRating>, loseteam: &Vec<SkillRating>, draw: bool)
+        -> (Vec<SkillRating>, Vec<SkillRating>) {
+        let c = self.c(winteam, loseteam);
+        let mu_w: f64 = winteam.iter().map(|r| r.mu as f64).sum();
+        let mu_l: f64 = loseteam.iter().map(|r| r.mu as f64).sum();
+        let t = (mu_w - mu_l) / c;
+        let e = self.draw_margin(winteam.len() + loseteam.len()) / c;
+        let (v, w) = if draw {
+            (v_draw(t, e) * if t < 0.0 { -1.0 } else { 1.0 }, w_draw(t, e))
+        } else {
+            (v_win(t, e), w_win(t, e))
+        };
+        let tau = self.tau as f64;
+        let update = |r: &SkillRating, sign: f64| {
+            let var = r.sigma as f64 * r.sigma as f64 + tau * tau;
+            let mu = r.mu as f64 + sign * var / c * v;
+            let sigma = (var * (1.0 - var / (c * c) * w).max(0.0001)).sqrt();
+            SkillRating { mu: mu as f32, sigma: sigma as f32 }
+        };
+        let wint = winteam.iter().map(|r| update(r, 1.0)).collect();
+        let loset = loseteam.iter().map(|r| update(r, -1.0)).collect();
+        (wint, loset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trueskill() {
+        let ts = TrueSkill::default();
+        let p = SkillRating::default();
+        // 1 vs 1 from the TrueSkill paper: 25/8.33 -> 29.40/7.17 and 20.60/7.17
+        let (w, l) = ts.compute_team(&vec![p], &vec![p], false);
+        println!("win {:?}, lose {:?}", w, l);
+        assert!((w[0].mu - 29.396 * 40.0).abs() < 2.0);
+        assert!((l[0].mu - 20.604 * 40.0).abs() < 2.0);
+        assert!((w[0].sigma - 7.171 * 40.0).abs() < 2.0);
+
+        let (w, l) = ts.compute_team(&vec![p], &vec![p], true);
+        println!("draw {:?}, {:?}", w, l);
+        assert!((w[0].mu - l[0].mu).abs() < 0.01);
+
+        // the uncertain player on the winning team moves further
+        let wint = vec![SkillRating {mu: 1000.0, sigma: 50.0}, p];
+        let loset = vec![SkillRating {mu: 1000.0, sigma: 50.0}; 2];
+        let (w, l) = ts.compute_team(&wint, &loset, false);
+        println!("win {:?}\nlose {:?}", w, l);
+        assert!(w[1].mu - 1000.0 > w[0].mu - 1000.0);
+    }
+}